@@ -0,0 +1,750 @@
+// Copyright 2016 Dario Domizioli
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use pulldown_cmark;
+use pulldown_cmark::{Event, Tag};
+use rustc_serialize::base64::{ToBase64, STANDARD};
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::prelude::*;
+use std::fs;
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+use regex;
+
+use structure::Content;
+
+// File extensions with their MIME types.
+const MIME_TYPES: [(&'static str, &'static str); 19] = [
+    ("png", "image/png"), ("jpg", "image/jpeg"), ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"), ("svg", "image/svg+xml"), ("webp", "image/webp"),
+    ("bmp", "image/bmp"), ("ico", "image/x-icon"), ("woff", "font/woff"),
+    ("woff2", "font/woff2"), ("ttf", "font/ttf"), ("otf", "font/otf"),
+    ("eot", "application/vnd.ms-fontobject"), ("css", "text/css"),
+    ("js", "application/javascript"), ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"), ("mp4", "video/mp4"), ("webm", "video/webm"),
+];
+
+// A `url(...)` reference in a stylesheet.
+const CSS_URL: &'static str = r#"url\(\s*['"]?([^'")]*)['"]?\s*\)"#;
+
+// The 64-bit FNV-1a hash of some content, which unlike the hashers of the
+// standard library is the same with every version of Rust, so that the names
+// of assets only change when their content does.
+fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes.iter() {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn mime_type(path: &str) -> &'static str {
+    let ext = Path::new(path).extension().map_or(String::new(), |e| {
+        e.to_string_lossy().to_lowercase()
+    });
+    MIME_TYPES.iter().find(|&&(e, _)| e == ext)
+              .map_or("application/octet-stream", |&(_, m)| m)
+}
+
+/// Whether a URL refers to a local file, rather than to a remote resource,
+/// an anchor or embedded data.
+pub fn is_local(url: &str) -> bool {
+    let re_scheme = regex::Regex::new("^[a-zA-Z][-+.a-zA-Z0-9]*:").unwrap();
+    !url.is_empty() && !url.starts_with('#') && !url.starts_with("//") &&
+    !re_scheme.is_match(url)
+}
+
+/// The path of the file a local URL refers to, relative to `base`: the
+/// query and fragment are dropped, and escapes such as `%20` and `&amp;`
+/// are decoded.
+pub fn local_path(url: &str, base: &Path) -> String {
+    let end = url.find(|c| c == '?' || c == '#').unwrap_or(url.len());
+    let url = url[..end].replace("&amp;", "&");
+    let bytes = url.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = String::from_utf8_lossy(&bytes[i + 1..i + 3]);
+            if let Ok(b) = u8::from_str_radix(&hex, 16) {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    let path = String::from_utf8_lossy(&decoded).into_owned();
+    base.join(path).to_string_lossy().into_owned()
+}
+
+// The content of a local file as a data URI, or None if it cannot be read.
+fn data_uri(path: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    match File::open(path) {
+        Ok(mut fread) => {
+            match fread.read_to_end(&mut bytes) {
+                Ok(_) => (),
+                Err(_) => { return None; }
+            }
+        },
+        Err(_) => { return None; }
+    }
+    Some("data:".to_string() + mime_type(path) + ";base64," +
+         &bytes.to_base64(STANDARD))
+}
+
+fn missing_error(missing: &Vec<String>) -> String {
+    "The following local assets could not be found: ".to_string() +
+    &missing.join(", ") + "."
+}
+
+/// Replaces the local URLs in `url(...)` references of a stylesheet, such as
+/// those of images and fonts, with data URIs. `css_path` is the path of the
+/// stylesheet, which the URLs are relative to.
+pub fn embed_css(css: &str, css_path: &str) -> Result<String, String> {
+    let re = regex::Regex::new(CSS_URL).unwrap();
+    let base = Path::new(css_path).parent().unwrap_or(Path::new(""));
+    let mut missing = Vec::new();
+    let res = re.replace_all(css, |cap: &regex::Captures| {
+        let url = cap.at(1).unwrap();
+        if !is_local(url) {
+            return cap.at(0).unwrap().to_string();
+        }
+        let path = local_path(url, base);
+        match data_uri(&path) {
+            Some(uri) => format!("url(\"{}\")", uri),
+            None => {
+                missing.push(path);
+                cap.at(0).unwrap().to_string()
+            }
+        }
+    });
+    if missing.is_empty() { Ok(res) } else { Err(missing_error(&missing)) }
+}
+
+/// Replaces the local URLs in the `src` attributes of an HTML page, and in
+/// the `href` attributes of its `<link>` elements, with data URIs. This is
+/// for references written as raw HTML, in the chapters or the theme, whose
+/// URLs are relative to the directory the program is invoked in; CommonMark
+/// images have already been embedded by `AssetPipeline::rewrite`.
+pub fn embed_html(html: &str) -> Result<String, String> {
+    let re_tag = regex::Regex::new(
+        r"<([a-zA-Z][a-zA-Z0-9]*)\b[^>]*>").unwrap();
+    let re_attr = regex::Regex::new(
+        r#"(\s)(src|href)="([^"]*)""#).unwrap();
+    let mut missing = Vec::new();
+    let res = re_tag.replace_all(html, |tag: &regex::Captures| {
+        let is_link = tag.at(1).unwrap().to_lowercase() == "link";
+        re_attr.replace_all(tag.at(0).unwrap(), |cap: &regex::Captures| {
+            let url = cap.at(3).unwrap();
+            if (cap.at(2).unwrap() == "href" && !is_link) || !is_local(url) {
+                return cap.at(0).unwrap().to_string();
+            }
+            let path = local_path(url, Path::new(""));
+            match data_uri(&path) {
+                Some(uri) => {
+                    format!("{}{}=\"{}\"", cap.at(1).unwrap(),
+                            cap.at(2).unwrap(), uri)
+                },
+                None => {
+                    missing.push(path);
+                    cap.at(0).unwrap().to_string()
+                }
+            }
+        })
+    });
+    if missing.is_empty() { Ok(res) } else { Err(missing_error(&missing)) }
+}
+
+// The file of an output directory listing the files written there by the
+// tool, relative to the directory, one per line.
+const MANIFEST: &'static str = ".kosbook_files";
+
+// Reads the manifest of an output directory, if any. Entries which do not
+// name a file inside the directory, such as `../book.md`, are ignored, so
+// that a damaged manifest cannot remove anything else.
+fn read_manifest(dir: &Path) -> Result<Vec<String>, String> {
+    let path = dir.join(MANIFEST);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut text = String::new();
+    match File::open(&path).and_then(|mut f| f.read_to_string(&mut text)) {
+        Ok(_) => (),
+        Err(e) => {
+            return Err(format!("Error reading '{}': {}. Please remove it, \
+                                along with any files it listed.",
+                               path.to_string_lossy(), e));
+        }
+    }
+    Ok(text.lines().filter(|l| {
+        !l.is_empty() && Path::new(l).components().all(|c| match c {
+            Component::Normal(_) => true,
+            _ => false
+        })
+    }).map(|l| l.to_string()).collect())
+}
+
+// Removes a file listed in a manifest, if it is still there.
+fn remove_listed(dir: &Path, name: &str) -> Result<(), String> {
+    let path = dir.join(name);
+    if !path.is_file() {
+        return Ok(());
+    }
+    match fs::remove_file(&path) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            Err(format!("Error removing '{}': {}", path.to_string_lossy(), e))
+        }
+    }
+}
+
+/// Creates an output directory. A directory containing any of the input
+/// files of the book (the structure file, chapter files, rules, theme and so
+/// on) is refused. If `clean` is set, the files written there by a previous
+/// run are removed first; other files are left alone.
+pub fn prepare_out_dir(dir: &str, clean: bool, inputs: &Vec<String>)
+-> Result<(), String> {
+    if let Ok(d) = fs::canonicalize(dir) {
+        for input in inputs.iter() {
+            let inside = fs::canonicalize(input).map(|i| {
+                i.starts_with(&d)
+            }).unwrap_or(false);
+            if inside {
+                return Err("Refusing to use output directory '".to_string() +
+                           dir + "', which contains the input file '" +
+                           input + "'.");
+            }
+        }
+    }
+    if clean {
+        let path = Path::new(dir);
+        let listed = match read_manifest(path) {
+            Ok(x) => x,
+            Err(e) => { return Err(e); }
+        };
+        for name in listed.iter().map(|l| l.as_str()).chain(Some(MANIFEST)) {
+            match remove_listed(path, name) {
+                Ok(_) => (),
+                Err(e) => { return Err(e); }
+            }
+        }
+        // The assets directory goes too, unless something else is in it.
+        let _ = fs::remove_dir(path.join("assets"));
+    }
+    match fs::create_dir_all(dir) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            Err(format!("Error creating output directory '{}': {}", dir, e))
+        }
+    }
+}
+
+/// Copies the local files referred to by the images and links of the book
+/// into an assets directory next to the output file, or, for standalone
+/// output, embeds the images as data URIs. When writing to an output
+/// directory, stylesheets and scripts are copied into the assets directory
+/// too.
+pub struct AssetPipeline {
+    // The directory files are copied to.
+    dir: PathBuf,
+    standalone: bool,
+    out_dir: bool,
+    // The new URL of each local file already processed.
+    urls: BTreeMap<String, String>,
+    // The names of the files in the assets directory used by the book.
+    stored: BTreeSet<String>,
+    // The other files written by the tool, such as the output HTML.
+    outputs: BTreeSet<String>,
+    // The files the book is read from, such as the chapter files.
+    inputs: BTreeSet<PathBuf>
+}
+
+// The canonical form of a directory, taking an empty path as the current
+// directory.
+fn canonical_dir(dir: &Path) -> Option<PathBuf> {
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    fs::canonicalize(dir).ok()
+}
+
+impl AssetPipeline {
+    pub fn new(output_file: &str, standalone: bool, out_dir: bool)
+    -> AssetPipeline {
+        let parent = Path::new(output_file).parent().unwrap_or(Path::new(""));
+        let mut pipeline = AssetPipeline {
+            dir: parent.join("assets"),
+            standalone: standalone,
+            out_dir: out_dir,
+            urls: BTreeMap::new(),
+            stored: BTreeSet::new(),
+            outputs: BTreeSet::new(),
+            inputs: BTreeSet::new()
+        };
+        pipeline.add_output(output_file);
+        pipeline
+    }
+
+    /// Records a file written next to the output HTML, such as the PDF, so
+    /// that it is listed in the manifest of an output directory.
+    pub fn add_output(&mut self, file: &str) {
+        if let Some(name) = Path::new(file).file_name() {
+            self.outputs.insert(name.to_string_lossy().into_owned());
+        }
+    }
+
+    /// Records a file the book is read from, such as a chapter file, so that
+    /// links to it are not taken for assets.
+    pub fn add_input(&mut self, file: &str) {
+        if let Ok(path) = fs::canonicalize(file) {
+            self.inputs.insert(path);
+        }
+    }
+
+    // Whether a local file is one the book is read from, or one written next
+    // to the output HTML, such as the PDF, which may not exist yet.
+    fn is_book_file(&self, path: &str) -> bool {
+        let path = Path::new(path);
+        if fs::canonicalize(path).map_or(false, |p| self.inputs.contains(&p)) {
+            return true;
+        }
+        let name = match path.file_name() {
+            Some(x) => x.to_string_lossy().into_owned(),
+            None => { return false; }
+        };
+        let out = self.dir.parent().unwrap_or(Path::new(""));
+        self.outputs.contains(&name) &&
+        path.parent().and_then(canonical_dir) == canonical_dir(out)
+    }
+
+    /// Whether the output is a single self-contained HTML file.
+    pub fn standalone(&self) -> bool {
+        self.standalone
+    }
+
+    // Writes the content of a file into the assets directory, with a hash of
+    // the content in its name so that different files with the same name do
+    // not collide. Files which are already there are left untouched.
+    fn store(&mut self, path: &str, bytes: &[u8]) -> Result<String, String> {
+        let file = Path::new(path);
+        let stem = file.file_stem().map_or("asset".to_string(), |s| {
+            s.to_string_lossy().into_owned()
+        });
+        let name = format!("{}-{:016x}", stem, content_hash(bytes)) +
+                   &file.extension().map_or(String::new(), |e| {
+                       ".".to_string() + &e.to_string_lossy()
+                   });
+        let target = self.dir.join(&name);
+        if !target.exists() {
+            match fs::create_dir_all(&self.dir)
+                  .and_then(|_| File::create(&target))
+                  .and_then(|mut f| f.write_all(bytes)) {
+                Ok(_) => (),
+                Err(e) => {
+                    return Err(format!("Error copying asset '{}': {}", path,
+                                       e));
+                }
+            }
+        }
+        self.stored.insert(name.clone());
+        Ok("assets/".to_string() + &name)
+    }
+
+    fn copy(&mut self, path: &str) -> Result<String, String> {
+        let mut bytes = Vec::new();
+        match File::open(path) {
+            Ok(mut fread) => {
+                match fread.read_to_end(&mut bytes) {
+                    Ok(_) => (),
+                    Err(_) => {
+                        return Err("Error reading asset '".to_string() +
+                                   path + "'.");
+                    }
+                }
+            },
+            Err(_) => {
+                return Err("Error opening asset '".to_string() + path + "'.");
+            }
+        }
+        self.store(path, &bytes)
+    }
+
+    /// The URL a stylesheet is linked with. When writing to an output
+    /// directory, the stylesheet is copied into the assets directory along
+    /// with the local files it refers to.
+    pub fn stylesheet(&mut self, path: &str) -> Result<String, String> {
+        if !self.out_dir {
+            return Ok(path.to_string());
+        }
+        let mut css = String::new();
+        match File::open(path) {
+            Ok(mut fread) => {
+                match fread.read_to_string(&mut css) {
+                    Ok(_) => (),
+                    Err(_) => {
+                        return Err("Error reading stylesheet '".to_string() +
+                                   path + "'.");
+                    }
+                }
+            },
+            Err(_) => {
+                return Err("Error opening stylesheet '".to_string() + path +
+                           "'.");
+            }
+        }
+        let re = regex::Regex::new(CSS_URL).unwrap();
+        let base = Path::new(path).parent().unwrap_or(Path::new(""));
+        let mut error = None;
+        let css = re.replace_all(&css, |cap: &regex::Captures| {
+            let url = cap.at(1).unwrap();
+            if !is_local(url) || error.is_some() {
+                return cap.at(0).unwrap().to_string();
+            }
+            let file = local_path(url, base);
+            match self.copy(&file) {
+                // The stylesheet is in the assets directory too.
+                Ok(x) => {
+                    format!("url(\"{}\")", x.trim_start_matches("assets/"))
+                },
+                Err(e) => {
+                    error = Some(e);
+                    cap.at(0).unwrap().to_string()
+                }
+            }
+        });
+        match error {
+            Some(e) => Err(e),
+            None => self.store(path, css.as_bytes())
+        }
+    }
+
+    /// The URL a script is loaded with. When writing to an output directory,
+    /// the script is copied into the assets directory.
+    pub fn script(&mut self, path: &str) -> Result<String, String> {
+        if self.out_dir { self.copy(path) } else { Ok(path.to_string()) }
+    }
+
+    /// The markup running a script generated by kosbook, such as the search
+    /// index. The script is stored in the assets directory, or inlined for
+    /// standalone output.
+    pub fn generated_script(&mut self, name: &str, js: &str)
+    -> Result<String, String> {
+        if self.standalone {
+            return Ok("<script>\n".to_string() + js.trim_end() +
+                      "\n</script>\n");
+        }
+        match self.store(name, js.as_bytes()) {
+            Ok(url) => Ok("<script src=\"".to_string() + &url +
+                          "\"></script>\n"),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// When writing to an output directory, removes the files written there
+    /// by a previous run which are not used by the book any more, and lists
+    /// those which are in the manifest of the directory.
+    pub fn prune(&self) -> Result<(), String> {
+        if !self.out_dir {
+            return Ok(());
+        }
+        let out = self.dir.parent().unwrap_or(Path::new(""));
+        let files: Vec<String> = self.outputs.iter().cloned().chain(
+            self.stored.iter().map(|n| "assets/".to_string() + n)).collect();
+        let listed = match read_manifest(out) {
+            Ok(x) => x,
+            Err(e) => { return Err(e); }
+        };
+        for name in listed.iter().filter(|l| !files.contains(l)) {
+            match remove_listed(out, name) {
+                Ok(_) => (),
+                Err(e) => { return Err(e); }
+            }
+        }
+        let text = files.join("\n") + "\n";
+        match File::create(out.join(MANIFEST))
+              .and_then(|mut f| f.write_all(text.as_bytes())) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Error writing '{}': {}", MANIFEST, e))
+        }
+    }
+
+    /// The new URL of an image or link found in `source` (a chapter file, or
+    /// a description in angle brackets for generated content), or None if it
+    /// is left as it is. Local URLs are relative to the source file, and a
+    /// local file which cannot be found is an error.
+    pub fn rewrite(&mut self, url: &str, source: &str, image: bool)
+    -> Result<Option<String>, String> {
+        if !is_local(url) || (self.standalone && !image) {
+            return Ok(None);
+        }
+        let base = if source.starts_with('<') {
+            Path::new("")
+        } else {
+            Path::new(source).parent().unwrap_or(Path::new(""))
+        };
+        let path = local_path(url, base);
+        // Links to directories, to other files of the book and to pages
+        // written by the tool are left alone. Standalone output cannot do
+        // without an image, and this is the last place its path is known
+        // relative to its source file.
+        if !self.standalone &&
+           (Path::new(&path).is_dir() || self.is_book_file(&path)) {
+            return Ok(None);
+        }
+        if !Path::new(&path).is_file() {
+            return Err(missing_error(&vec![path]));
+        }
+        // Keep the fragment of a copied file, as in file.pdf#page=2.
+        let suffix = if self.standalone {
+            ""
+        } else {
+            url.find('#').map_or("", |i| &url[i..])
+        };
+        if let Some(new_url) = self.urls.get(&path) {
+            return Ok(Some(new_url.clone() + suffix));
+        }
+        let new_url = if self.standalone {
+            match data_uri(&path) {
+                Some(x) => x,
+                None => { return Err(missing_error(&vec![path])); }
+            }
+        } else {
+            match self.copy(&path) {
+                Ok(x) => x,
+                Err(e) => { return Err(e); }
+            }
+        };
+        self.urls.insert(path, new_url.clone());
+        Ok(Some(new_url + suffix))
+    }
+
+    /// Parses the CommonMark content of the book, rewriting the URLs of
+    /// images and links to local files.
+    pub fn process<'a>(&mut self, text: &'a str,
+                       opts: pulldown_cmark::Options, content: &Content)
+    -> Result<Vec<Event<'a>>, String> {
+        let mut events = Vec::new();
+        let mut parser = pulldown_cmark::Parser::new_ext(text, opts);
+        while let Some(event) = parser.next() {
+            let source = content.source_at(parser.get_offset());
+            let event = match event {
+                Event::Start(Tag::Image(url, title)) => {
+                    match self.rewrite(&url, source, true) {
+                        Ok(Some(x)) => {
+                            Event::Start(Tag::Image(Cow::Owned(x), title))
+                        },
+                        Ok(None) => Event::Start(Tag::Image(url, title)),
+                        Err(e) => { return Err(e); }
+                    }
+                },
+                Event::Start(Tag::Link(url, title)) => {
+                    match self.rewrite(&url, source, false) {
+                        Ok(Some(x)) => {
+                            Event::Start(Tag::Link(Cow::Owned(x), title))
+                        },
+                        Ok(None) => Event::Start(Tag::Link(url, title)),
+                        Err(e) => { return Err(e); }
+                    }
+                },
+                e => e
+            };
+            events.push(event);
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::{Path, PathBuf};
+
+    use super::{content_hash, embed_css, embed_html, prepare_out_dir,
+                AssetPipeline, MANIFEST};
+
+    // A fresh directory for a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join("kosbook_".to_string() + name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, text: &str) {
+        fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| File::create(path))
+            .and_then(|mut f| f.write_all(text.as_bytes())).unwrap();
+    }
+
+    fn path(p: &Path) -> String {
+        p.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn standalone() {
+        let dir = temp_dir("standalone");
+        write(&dir.join("ch").join("pic.png"), "PNG");
+        let png = "data:image/png;base64,UE5H";
+        let css_path = path(&dir.join("ch").join("style.css"));
+        assert_eq!(embed_css("a { b: url('pic.png') url(http://x/y.png) }",
+                             &css_path),
+                   Ok(format!("a {{ b: url(\"{}\") url(http://x/y.png) }}",
+                              png)));
+        let e = embed_css("a { b: url(none.png) }", &css_path).unwrap_err();
+        assert!(e.contains(&path(&dir.join("ch").join("none.png"))), "{}", e);
+
+        let mut pipeline = AssetPipeline::new(&path(&dir.join("out.html")),
+                                              true, false);
+        let source = path(&dir.join("ch").join("a.md"));
+        assert_eq!(pipeline.rewrite("pic.png", &source, true),
+                   Ok(Some(png.to_string())));
+        assert_eq!(pipeline.rewrite("pic.png", &source, false), Ok(None));
+        assert_eq!(pipeline.rewrite("http://x/y.png", &source, true),
+                   Ok(None));
+        // A missing image is named relative to its source file.
+        let e = pipeline.rewrite("none.png", &source, true).unwrap_err();
+        assert!(e.contains(&path(&dir.join("ch").join("none.png"))), "{}", e);
+        assert!(pipeline.rewrite("../ch", &source, true).is_err());
+        assert!(!dir.join("assets").exists());
+        assert_eq!(pipeline.generated_script("a.js", "x();\n\n"),
+                   Ok("<script>\nx();\n</script>\n".to_string()));
+
+        let pic = path(&dir.join("ch").join("pic.png"));
+        assert_eq!(embed_html(&format!(
+                       "<img alt=\"x\" src=\"{0}\"> <a href=\"{0}\">a</a> \
+                        <link rel=\"icon\" href=\"{0}\"> \
+                        <img src=\"{1}\">", pic, png)),
+                   Ok(format!("<img alt=\"x\" src=\"{0}\"> \
+                               <a href=\"{1}\">a</a> \
+                               <link rel=\"icon\" href=\"{0}\"> \
+                               <img src=\"{0}\">", png, pic)));
+        assert!(embed_html("<img src=\"no_such.png\">").is_err());
+    }
+
+    #[test]
+    fn copying() {
+        let dir = temp_dir("copying");
+        write(&dir.join("ch").join("pic.png"), "PNG");
+        for name in ["a.md", "b.md"].iter() {
+            write(&dir.join("ch").join(name), "");
+        }
+        fs::create_dir_all(dir.join("ch").join("sub")).unwrap();
+        let out = dir.join("out");
+        let mut pipeline = AssetPipeline::new(&path(&out.join("book.html")),
+                                              false, false);
+        pipeline.add_output(&path(&out.join("book.pdf")));
+        pipeline.add_input(&path(&dir.join("ch").join("b.md")));
+        let source = path(&dir.join("ch").join("a.md"));
+        let copy = format!("assets/pic-{:016x}.png", content_hash(b"PNG"));
+        assert_eq!(pipeline.rewrite("pic.png", &source, true),
+                   Ok(Some(copy.clone())));
+        assert_eq!(pipeline.rewrite("pic.png#x", &source, false),
+                   Ok(Some(copy.clone() + "#x")));
+        assert!(out.join(&copy).is_file());
+        // Other chapter files, directories and generated pages.
+        for url in ["b.md", "sub", "../out/book.pdf"].iter() {
+            assert_eq!(pipeline.rewrite(url, &source, false), Ok(None));
+        }
+        assert_eq!(fs::read_dir(out.join("assets")).unwrap().count(), 1);
+        let e = pipeline.rewrite("none.png", &source, false).unwrap_err();
+        assert!(e.contains(&path(&dir.join("ch").join("none.png"))), "{}", e);
+        assert!(pipeline.rewrite("../out/book.png", &source, false).is_err());
+    }
+
+    #[test]
+    fn clean_out_dir() {
+        let root = temp_dir("clean");
+        let out = root.join("out");
+        for name in ["index.html", "user.txt", "assets/old.png",
+                     "assets/user.png"].iter() {
+            write(&out.join(name), "x");
+        }
+        write(&root.join("outside.txt"), "x");
+        write(&out.join(MANIFEST),
+              "index.html\nassets/old.png\n../outside.txt\n\n");
+        let no_inputs = Vec::new();
+        prepare_out_dir(&path(&out), true, &no_inputs).unwrap();
+        // Only the files listed in the manifest, inside the directory, go.
+        assert!(!out.join("index.html").exists());
+        assert!(!out.join("assets").join("old.png").exists());
+        assert!(!out.join(MANIFEST).exists());
+        assert!(out.join("user.txt").exists());
+        assert!(out.join("assets").join("user.png").exists());
+        assert!(root.join("outside.txt").exists());
+        // Without a manifest, nothing is removed.
+        prepare_out_dir(&path(&out), true, &no_inputs).unwrap();
+        assert!(out.join("user.txt").exists());
+        // An unreadable manifest is an error, and removes nothing.
+        File::create(out.join(MANIFEST))
+            .and_then(|mut f| f.write_all(b"user.txt\n\xff\n")).unwrap();
+        assert!(prepare_out_dir(&path(&out), true, &no_inputs).is_err());
+        assert!(out.join("user.txt").exists());
+        // A new directory is created.
+        prepare_out_dir(&path(&root.join("new")), false, &no_inputs).unwrap();
+        assert!(root.join("new").is_dir());
+    }
+
+    #[test]
+    fn input_in_out_dir() {
+        let root = temp_dir("inputs");
+        write(&root.join("book").join("a.md"), "");
+        let inputs = vec![path(&root.join("book").join("a.md"))];
+        assert!(prepare_out_dir(&path(&root), false, &inputs).is_err());
+        assert!(prepare_out_dir(&path(&root.join("book")), true, &inputs)
+                .is_err());
+        assert!(prepare_out_dir(&path(&root.join("out")), false, &inputs)
+                .is_ok());
+    }
+
+    #[test]
+    fn prune() {
+        let root = temp_dir("prune");
+        write(&root.join("ch").join("pic.png"), "PNG");
+        let out = root.join("out");
+        for name in ["index.html", "index.pdf", "user.txt",
+                     "assets/stale.png"].iter() {
+            write(&out.join(name), "x");
+        }
+        write(&out.join(MANIFEST),
+              "index.html\nindex.pdf\nassets/stale.png\n");
+        let mut pipeline = AssetPipeline::new(&path(&out.join("index.html")),
+                                              false, true);
+        let source = path(&root.join("ch").join("a.md"));
+        let copy = pipeline.rewrite("pic.png", &source, true).unwrap()
+                           .unwrap();
+        pipeline.prune().unwrap();
+        assert!(!out.join("assets").join("stale.png").exists());
+        assert!(!out.join("index.pdf").exists());
+        assert!(out.join("index.html").exists());
+        assert!(out.join("user.txt").exists());
+        let mut manifest = String::new();
+        File::open(out.join(MANIFEST))
+            .and_then(|mut f| f.read_to_string(&mut manifest)).unwrap();
+        assert_eq!(manifest, "index.html\n".to_string() + &copy + "\n");
+        // Pipelines which do not write to an output directory leave it
+        // alone.
+        let pipeline = AssetPipeline::new(&path(&out.join("index.html")),
+                                          false, false);
+        fs::remove_file(out.join(MANIFEST)).unwrap();
+        pipeline.prune().unwrap();
+        assert!(!out.join(MANIFEST).exists());
+    }
+}
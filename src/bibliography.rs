@@ -0,0 +1,355 @@
+// Copyright 2016 Dario Domizioli
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rustc_serialize::json;
+
+use std::collections::BTreeMap;
+use regex;
+
+use markdown::map_text_segments;
+
+/// How citations and the list of references are rendered.
+#[derive(Clone, Copy, PartialEq)]
+enum CitationStyle {
+    // [1], with references listed in order of first citation.
+    Numeric,
+    // (Knuth 1984), with references listed by author and year.
+    AuthorYear,
+}
+
+/// A bibliography entry, with the fields of CSL-JSON that are rendered.
+struct Entry {
+    authors: Vec<String>,
+    year: String,
+    title: String,
+    container: String,
+    publisher: String,
+    url: String,
+}
+
+/// The entries of a bibliography file, and which of them have been cited.
+pub struct Bibliography {
+    entries: BTreeMap<String, Entry>,
+    style: CitationStyle,
+    // Cited keys, in order of first citation.
+    cited: Vec<String>,
+}
+
+fn string_field(item: &json::Json, field: &str) -> String {
+    item.find(field).and_then(|v| v.as_string()).unwrap_or("").to_string()
+}
+
+fn decode_entry(item: &json::Json) -> Entry {
+    let authors = match item.find("author").and_then(|a| a.as_array()) {
+        Some(names) => names.iter().map(|n| {
+            let family = string_field(n, "family");
+            if family.is_empty() {
+                string_field(n, "literal")
+            } else {
+                let given = string_field(n, "given");
+                if given.is_empty() { family } else { family + ", " + &given }
+            }
+        }).filter(|n| !n.is_empty()).collect(),
+        None => Vec::new()
+    };
+    let year = item.find_path(&["issued", "date-parts"])
+        .and_then(|d| d.as_array()).and_then(|d| d.get(0))
+        .and_then(|d| d.as_array()).and_then(|d| d.get(0))
+        .and_then(|y| match *y {
+            json::Json::I64(n) => Some(format!("{}", n)),
+            json::Json::U64(n) => Some(format!("{}", n)),
+            json::Json::String(ref s) => Some(s.clone()),
+            _ => None
+        }).unwrap_or("n.d.".to_string());
+    Entry {
+        authors: authors,
+        year: year,
+        title: string_field(item, "title"),
+        container: string_field(item, "container-title"),
+        publisher: string_field(item, "publisher"),
+        url: string_field(item, "URL")
+    }
+}
+
+fn cite_id(key: &str) -> String {
+    let re = regex::Regex::new("[^-_0-9a-zA-Z]").unwrap();
+    "kos_cite_".to_string() + &re.replace_all(key, "_")
+}
+
+impl Entry {
+    // The family name of an author, as used in author-year citations.
+    fn family_names(&self) -> Vec<&str> {
+        self.authors.iter().map(|a| a.split(',').next().unwrap()).collect()
+    }
+
+    fn author_year(&self) -> String {
+        let names = self.family_names();
+        let authors = match names.len() {
+            0 => self.title.clone(),
+            1 => names[0].to_string(),
+            2 => names[0].to_string() + " and " + names[1],
+            _ => names[0].to_string() + " et al."
+        };
+        authors + " " + &self.year
+    }
+
+    fn reference(&self) -> String {
+        let mut res = String::new();
+        if !self.authors.is_empty() {
+            res = res + &self.authors.join("; ") + " ";
+        }
+        res = res + "(" + &self.year + ").";
+        for (text, emphasis) in vec![(&self.title, true),
+                                     (&self.container, false),
+                                     (&self.publisher, false)] {
+            if !text.is_empty() {
+                res = res + if emphasis { " *" } else { " " } + text +
+                      if emphasis { "*." } else { "." };
+            }
+        }
+        if !self.url.is_empty() {
+            res = res + " <" + &self.url + ">";
+        }
+        res
+    }
+}
+
+impl Bibliography {
+    /// Reads a CSL-JSON bibliography, that is an array of items each with
+    /// an "id" field. `style` is "numeric" or "author-year".
+    pub fn from_csl_json(js: &str, style: &str)
+    -> Result<Bibliography, String> {
+        let style = match style {
+            "numeric" => CitationStyle::Numeric,
+            "author-year" => CitationStyle::AuthorYear,
+            _ => {
+                return Err("Citation style '".to_string() + style + "' is " +
+                           "invalid. Please use 'numeric' or 'author-year'.");
+            }
+        };
+        let root = match json::Json::from_str(js) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Error parsing bibliography JSON: {}", e));
+            }
+        };
+        let items = match root.as_array() {
+            Some(x) => x,
+            None => {
+                return Err("Error parsing bibliography JSON: the top level \
+                            value must be an array.".to_string());
+            }
+        };
+        let mut entries = BTreeMap::new();
+        for item in items.iter() {
+            let key = match item.find("id").and_then(|i| i.as_string()) {
+                Some(x) => x.to_string(),
+                None => {
+                    return Err("Error parsing bibliography JSON: every item \
+                                must have a string \"id\" field."
+                               .to_string());
+                }
+            };
+            if entries.insert(key.clone(), decode_entry(item)).is_some() {
+                return Err("Bibliography entry '".to_string() + &key +
+                           "' is defined more than once.");
+            }
+        }
+        Ok(Bibliography {
+            entries: entries,
+            style: style,
+            cited: Vec::new()
+        })
+    }
+
+    /// Renders the citations in plain text, such as `[@knuth1984]`,
+    /// `[@knuth1984, p. 33]` or `[@knuth1984; @lamport1986]`, as links to
+    /// the references.
+    pub fn cite(&mut self, chunk: &str, source: &str)
+    -> Result<String, String> {
+        let re = regex::Regex::new(r"\[(@[^\]]*)\](\(|\[)?").unwrap();
+        let re_item = regex::Regex::new(r"^@([^\s,;]+)(?:,\s*(.*))?$")
+                      .unwrap();
+        let mut error = None;
+        let res = map_text_segments(chunk, |text| {
+            re.replace_all(text, |cap: &regex::Captures| {
+                let whole = cap.at(0).unwrap();
+                // [@text](url) and [@text][ref] are links, not citations.
+                if cap.at(2).is_some() || error.is_some() {
+                    return whole.to_string();
+                }
+                let mut parts = Vec::new();
+                for item in cap.at(1).unwrap().split(';') {
+                    let item = match re_item.captures(item.trim()) {
+                        Some(x) => x,
+                        None => { return whole.to_string(); }
+                    };
+                    let key = item.at(1).unwrap();
+                    let entry = match self.entries.get(key) {
+                        Some(x) => x,
+                        None => {
+                            error = Some("Citation of unknown bibliography \
+                                          entry '".to_string() + key +
+                                         "' in " + source + ".");
+                            return whole.to_string();
+                        }
+                    };
+                    if !self.cited.iter().any(|c| c == key) {
+                        self.cited.push(key.to_string());
+                    }
+                    let label = match self.style {
+                        CitationStyle::Numeric => {
+                            let n = self.cited.iter().position(|c| c == key)
+                                    .unwrap() + 1;
+                            format!("{}", n)
+                        },
+                        CitationStyle::AuthorYear => entry.author_year()
+                    };
+                    let mut part = format!("<a class=\"citation\" \
+                                            href=\"#{}\">{}</a>",
+                                           cite_id(key), label);
+                    if let Some(locator) = item.at(2) {
+                        part = part + ", " + locator;
+                    }
+                    parts.push(part);
+                }
+                match self.style {
+                    CitationStyle::Numeric => {
+                        "[".to_string() + &parts.join("; ") + "]"
+                    },
+                    CitationStyle::AuthorYear => {
+                        "(".to_string() + &parts.join("; ") + ")"
+                    }
+                }
+            })
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(res)
+        }
+    }
+
+    /// Whether any entry has been cited.
+    pub fn has_citations(&self) -> bool {
+        !self.cited.is_empty()
+    }
+
+    /// The References section, listing only the cited entries.
+    pub fn references(&self) -> String {
+        let mut keys = self.cited.clone();
+        if self.style == CitationStyle::AuthorYear {
+            keys.sort_by_key(|k| {
+                let e = &self.entries[k];
+                (e.family_names().join(" ").to_lowercase(), e.year.clone())
+            });
+        }
+        let mut res = "# <a id=\"kos_references\">References</a>\n\n\
+                       <div class=\"book_references\">\n\n".to_string();
+        for (i, key) in keys.iter().enumerate() {
+            res = res + "<a id=\"" + &cite_id(key) + "\"></a>";
+            if self.style == CitationStyle::Numeric {
+                res = res + &format!("\\[{}\\] ", i + 1);
+            }
+            res = res + &self.entries[key].reference() + "\n\n";
+        }
+        res + "</div>\n\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bibliography;
+
+    const ITEMS: &'static str = r#"[
+        {"id": "knuth1984", "title": "Literate Programming",
+         "container-title": "The Computer Journal",
+         "author": [{"family": "Knuth", "given": "Donald"}],
+         "issued": {"date-parts": [[1984]]}},
+        {"id": "lamport1986", "title": "LaTeX", "publisher": "Addison-Wesley",
+         "author": [{"family": "Lamport", "given": "Leslie"}],
+         "issued": {"date-parts": [["1986"]]}},
+        {"id": "kr", "title": "The C Programming Language",
+         "author": [{"family": "Kernighan"}, {"family": "Ritchie"}]},
+        {"id": "many", "title": "Many Hands", "URL": "http://example.com",
+         "author": [{"family": "A"}, {"family": "B"}, {"literal": "C Inc."}],
+         "issued": {"date-parts": [[2001, 5]]}}
+    ]"#;
+
+    #[test]
+    fn numeric_citations() {
+        let mut bib = Bibliography::from_csl_json(ITEMS, "numeric").unwrap();
+        assert!(!bib.has_citations());
+        let text = bib.cite("See [@lamport1986, p. 3; @knuth1984], \
+                             [@lamport1986] and `[@knuth1984]`.", "a.md")
+                      .unwrap();
+        assert_eq!(text,
+                   "See [<a class=\"citation\" href=\"#kos_cite_lamport1986\">\
+                    1</a>, p. 3; <a class=\"citation\" \
+                    href=\"#kos_cite_knuth1984\">2</a>], \
+                    [<a class=\"citation\" href=\"#kos_cite_lamport1986\">\
+                    1</a>] and `[@knuth1984]`.");
+        assert!(bib.has_citations());
+        assert_eq!(bib.references(),
+                   "# <a id=\"kos_references\">References</a>\n\n\
+                    <div class=\"book_references\">\n\n\
+                    <a id=\"kos_cite_lamport1986\"></a>\\[1\\] \
+                    Lamport, Leslie (1986). *LaTeX*. Addison-Wesley.\n\n\
+                    <a id=\"kos_cite_knuth1984\"></a>\\[2\\] \
+                    Knuth, Donald (1984). *Literate Programming*. \
+                    The Computer Journal.\n\n</div>\n\n");
+    }
+
+    #[test]
+    fn author_year_citations() {
+        let mut bib = Bibliography::from_csl_json(ITEMS, "author-year")
+                      .unwrap();
+        let text = bib.cite("[@many] [@kr] [@knuth1984]", "a.md").unwrap();
+        assert_eq!(text,
+                   "(<a class=\"citation\" href=\"#kos_cite_many\">A et al. \
+                    2001</a>) (<a class=\"citation\" href=\"#kos_cite_kr\">\
+                    Kernighan and Ritchie n.d.</a>) (<a class=\"citation\" \
+                    href=\"#kos_cite_knuth1984\">Knuth 1984</a>)");
+        // Sorted by author, without numbers.
+        assert_eq!(bib.references(),
+                   "# <a id=\"kos_references\">References</a>\n\n\
+                    <div class=\"book_references\">\n\n\
+                    <a id=\"kos_cite_many\"></a>A; B; C Inc. (2001). \
+                    *Many Hands*. <http://example.com>\n\n\
+                    <a id=\"kos_cite_kr\"></a>Kernighan; Ritchie (n.d.). \
+                    *The C Programming Language*.\n\n\
+                    <a id=\"kos_cite_knuth1984\"></a>Knuth, Donald (1984). \
+                    *Literate Programming*. The Computer Journal.\n\n\
+                    </div>\n\n");
+    }
+
+    #[test]
+    fn not_citations() {
+        let mut bib = Bibliography::from_csl_json(ITEMS, "numeric").unwrap();
+        let text = "[@kr](http://x) [@kr][ref] [@ not a key] mail@kr";
+        assert_eq!(bib.cite(text, "a.md").unwrap(), text);
+        assert!(!bib.has_citations());
+    }
+
+    #[test]
+    fn errors() {
+        let mut bib = Bibliography::from_csl_json(ITEMS, "numeric").unwrap();
+        assert!(bib.cite("[@nobody1999]", "a.md").is_err());
+        assert!(Bibliography::from_csl_json(ITEMS, "harvard").is_err());
+        assert!(Bibliography::from_csl_json("{}", "numeric").is_err());
+        assert!(Bibliography::from_csl_json(r#"[{"title": "x"}]"#, "numeric")
+                .is_err());
+        assert!(Bibliography::from_csl_json(r#"[{"id": "a"}, {"id": "a"}]"#,
+                                            "numeric").is_err());
+    }
+}
@@ -0,0 +1,770 @@
+// Copyright 2016 Dario Domizioli
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use regex;
+
+use std::collections::BTreeMap;
+
+use markdown;
+use markdown::map_text_segments;
+use rules;
+use rules::{CounterScope, RuleSpec, StorageSpec, StorageAction};
+use structure;
+
+/// Built-in rules which cannot be expressed as a plain regex replacement.
+#[derive(Clone, PartialEq)]
+pub enum NativeRule {
+    FigureNumbering,
+    Abbreviations,
+    SmartQuotes,
+    CrossReferences,
+    Index,
+}
+
+const ADMONITIONS: [(&'static str, &'static str, &'static str); 5] = [
+    ("NOTE", "note", "Note"),
+    ("TIP", "tip", "Tip"),
+    ("IMPORTANT", "important", "Important"),
+    ("WARNING", "warning", "Warning"),
+    ("CAUTION", "caution", "Caution"),
+];
+
+// The body of an admonition runs to the next blank line or code fence.
+const ADMONITION_BODY: &'static str = concat!(
+    r"[^\n]+(?:\n[ \t]*",
+    r"(?:[^ \t`~\n]|`[^`\n]|``[^`\n]|~[^~\n]|~~[^~\n])[^\n]*)*\n?");
+
+fn admonition_rules() -> Vec<rules::Rule> {
+    ADMONITIONS.iter().map(|&(marker, class, label)| {
+        rules::Rule::Spec(RuleSpec::new(
+            &format!("Built-in admonition ({})", marker),
+            &format!(r"(?m)^{}:[ \t]+({})", marker, ADMONITION_BODY),
+            &format!("<div class=\"admonition admonition_{}\">\n\n\
+                      **{}:** $1\n\n</div>\n\n", class, label),
+            Vec::new(), "text-only"))
+    }).collect()
+}
+
+fn glossary_rules() -> Vec<rules::Rule> {
+    vec![rules::Rule::Spec(RuleSpec::new(
+        "Built-in glossary",
+        r"(?m)^\[glossary:([_0-9a-zA-Z]+)\]:[ \t]*(.*)$",
+        "",
+        vec![StorageSpec::new(StorageAction::StoreMapSingle,
+                              "$2", "glossary", "$1")],
+        "text-only"))]
+}
+
+fn todo_marker_rules() -> Vec<rules::Rule> {
+    vec![rules::Rule::Spec(RuleSpec::new(
+        "Built-in TODO markers",
+        r"(?m)\b(TODO|FIXME|XXX):[ \t]*(.*)$",
+        "<span class=\"todo_marker\">$1: $2</span>",
+        vec![StorageSpec::new(StorageAction::StoreVector,
+                              "- $1: $2", "todo_list", "")],
+        "text-only"))]
+}
+
+/// Looks up a built-in rule set by the name used in the rules file.
+pub fn lookup(name: &str) -> Result<Vec<rules::Rule>, String> {
+    match name {
+        "admonitions" => Ok(admonition_rules()),
+        "glossary" => Ok(glossary_rules()),
+        "todo-markers" => Ok(todo_marker_rules()),
+        "figure-numbering" => {
+            Ok(vec![rules::Rule::Native(NativeRule::FigureNumbering)])
+        },
+        "abbreviations" => {
+            Ok(vec![rules::Rule::Native(NativeRule::Abbreviations)])
+        },
+        "smart-quotes" => {
+            Ok(vec![rules::Rule::Native(NativeRule::SmartQuotes)])
+        },
+        "cross-references" => {
+            Ok(vec![rules::Rule::Native(NativeRule::CrossReferences)])
+        },
+        "index" => Ok(vec![rules::Rule::Native(NativeRule::Index)]),
+        _ => Err("Unknown built-in rule '".to_string() + name + "'.")
+    }
+}
+
+fn apply_figure_numbering(engine: &mut rules::RulesEngine,
+                          content: &mut structure::Content)
+-> Result<(), String> {
+    let re = regex::Regex::new(
+        r"(?m)^!\[([^\]]*)\]\(([^)]*)\)[ \t]*$").unwrap();
+    let mut figure_index = 0;
+    let mut captions = Vec::new();
+    for chunk in content.chunks.iter_mut() {
+        let new_chunk = re.replace_all(&chunk, |cap: &regex::Captures| {
+            figure_index += 1;
+            let caption = cap.at(1).unwrap_or("");
+            captions.push(format!("Figure {}: {}", figure_index, caption));
+            format!("<div class=\"figure\" id=\"kos_fig_{0}\">\n\n\
+                     {1}\n\n\
+                     <div class=\"figure_caption\">Figure {0}: {2}</div>\n\n\
+                     </div>",
+                    figure_index, cap.at(0).unwrap(), caption)
+        });
+        chunk.clear();
+        chunk.push_str(&new_chunk);
+    }
+    for c in captions.into_iter() {
+        match engine.store_value(&StorageAction::StoreVector, "figure_list",
+                                 String::new(), c, CounterScope::Global, "") {
+            Ok(_) => (),
+            Err(e) => { return Err(e); }
+        }
+    }
+    Ok(())
+}
+
+fn apply_abbreviations(engine: &mut rules::RulesEngine,
+                       content: &mut structure::Content)
+-> Result<(), String> {
+    // First collect (and remove) every definition in the book, so that
+    // abbreviations can be used before the line defining them.
+    let re_def = regex::Regex::new(r"(?m)^\*\[([^\]]+)\]:[ \t]*(.*)\n?")
+                 .unwrap();
+    let mut abbrevs = Vec::new();
+    for chunk in content.chunks.iter_mut() {
+        for cap in re_def.captures_iter(&chunk) {
+            abbrevs.push((cap.at(1).unwrap().to_string(),
+                          cap.at(2).unwrap().trim().to_string()));
+        }
+        let new_chunk = re_def.replace_all(&chunk, "");
+        chunk.clear();
+        chunk.push_str(&new_chunk);
+    }
+    if abbrevs.is_empty() {
+        return Ok(());
+    }
+    let mut names: Vec<&str> = abbrevs.iter().map(|&(ref a, _)| a.as_str())
+                                      .collect();
+    let re_use = regex::Regex::new(
+        &markdown::word_bounded_alternation(&mut names)).unwrap();
+    for chunk in content.chunks.iter_mut() {
+        let new_chunk = map_text_segments(&chunk, |text| {
+            re_use.replace_all(text, |cap: &regex::Captures| {
+                let abbr = cap.at(0).unwrap();
+                let title = abbrevs.iter().find(|&&(ref a, _)| a == abbr)
+                            .map_or("", |&(_, ref t)| t.as_str());
+                format!("<abbr title=\"{}\">{}</abbr>",
+                        title.replace("\"", "&quot;"), abbr)
+            })
+        });
+        chunk.clear();
+        chunk.push_str(&new_chunk);
+    }
+    let valid_key = regex::Regex::new("^[_0-9a-zA-Z]+$").unwrap();
+    for (abbr, title) in abbrevs.into_iter() {
+        // Abbreviations like "e.g." cannot be map keys; they are still
+        // expanded, just not stored.
+        if !valid_key.is_match(&abbr) {
+            continue;
+        }
+        match engine.store_value(&StorageAction::StoreMapSingle,
+                                 "abbreviations", abbr, title,
+                                 CounterScope::Global, "") {
+            Ok(_) => (),
+            Err(e) => { return Err(e); }
+        }
+    }
+    Ok(())
+}
+
+fn smarten(text: &str, prev: &mut char) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        let opening = prev.is_whitespace() || "([{-\u{2014}".contains(*prev);
+        match c {
+            '"' => out.push(if opening { '\u{201C}' } else { '\u{201D}' }),
+            '\'' => out.push(if opening { '\u{2018}' } else { '\u{2019}' }),
+            _ => out.push(c)
+        }
+        *prev = c;
+    }
+    out
+}
+
+fn apply_smart_quotes(content: &mut structure::Content)
+-> Result<(), String> {
+    for chunk in content.chunks.iter_mut() {
+        let mut prev = ' ';
+        let new_chunk = map_text_segments(&chunk, |text| {
+            smarten(text, &mut prev)
+        });
+        chunk.clear();
+        chunk.push_str(&new_chunk);
+    }
+    Ok(())
+}
+
+// A cross-reference target: the text and anchor id references resolve to.
+struct Label {
+    text: String,
+    id: String,
+    source: String,
+}
+
+fn in_code(segs: &Vec<(markdown::SegmentKind, usize, usize)>, pos: usize)
+-> bool {
+    segs.iter().any(|&(kind, start, end)| {
+        kind == markdown::SegmentKind::Code && start <= pos && pos < end
+    })
+}
+
+fn define_label(labels: &mut BTreeMap<String, Label>, label: String,
+                text: String, source: &str) -> Result<String, String> {
+    let id = "kos_ref_".to_string() + &label.replace(":", "_");
+    if let Some(l) = labels.get(&label) {
+        return Err("Label '".to_string() + &label + "' is defined both in " +
+                   &l.source + " and in " + source + ".");
+    }
+    labels.insert(label, Label {
+        text: text,
+        id: id.clone(),
+        source: source.to_string()
+    });
+    Ok(id)
+}
+
+fn apply_cross_references(content: &mut structure::Content)
+-> Result<(), String> {
+    // Section labels end ATX headings, before any closing hashes.
+    let re_heading = regex::Regex::new(concat!(
+        r"(?m)^(#{1,6})[ \t]+(.*?)[ \t]*\{#sec:([-_0-9a-zA-Z]+)\}",
+        r"(?:[ \t]+#+)?[ \t]*$")).unwrap();
+    let re_figure = regex::Regex::new(
+        r"(?m)^(!\[([^\]]*)\]\([^)]*\))[ \t]*\{#(fig:[-_0-9a-zA-Z]+)\}[ \t]*$")
+        .unwrap();
+    let re_caption = regex::Regex::new(concat!(
+        r"(?m)^(Table|Listing):[ \t]*(.*?)[ \t]*",
+        r"\{#((tbl|lst):[-_0-9a-zA-Z]+)\}[ \t]*$")).unwrap();
+    let re_ref = regex::Regex::new(concat!(
+        r"\[\[((?:sec|fig|tbl|lst):[-_0-9a-zA-Z]+)\]\]|",
+        r"\B@((?:sec|fig|tbl|lst):[-_0-9a-zA-Z]+)")).unwrap();
+    // First collect (and replace) every label in the book, so that
+    // references can appear before their target.
+    let mut labels = BTreeMap::new();
+    // Per kind of label, the chapter being numbered and the last number.
+    let mut numbers: BTreeMap<&'static str, (String, usize)> = BTreeMap::new();
+    let mut error = None;
+    for ((chunk, source), chapter) in content.chunks.iter_mut()
+                                      .zip(content.sources.iter())
+                                      .zip(content.chapters.iter()) {
+        let mut next_number = |kind: &'static str| {
+            let entry = numbers.entry(kind).or_insert((chapter.clone(), 0));
+            if entry.0 != *chapter {
+                *entry = (chapter.clone(), 0);
+            }
+            entry.1 += 1;
+            if chapter.is_empty() {
+                format!("{}", entry.1)
+            } else {
+                format!("{}.{}", chapter, entry.1)
+            }
+        };
+        let segs = markdown::segments(&chunk);
+        let new_chunk = re_heading.replace_all(&chunk, |cap: &regex::Captures| {
+            let whole = cap.at(0).unwrap();
+            if error.is_some() || in_code(&segs, cap.pos(0).unwrap().0) {
+                return whole.to_string();
+            }
+            // The heading gets the anchor of the label, and the label is
+            // removed from its text.
+            let label = "sec:".to_string() + cap.at(3).unwrap();
+            let title = cap.at(2).unwrap();
+            match define_label(&mut labels, label, title.to_string(), source) {
+                Ok(id) => {
+                    format!("{} <a id=\"{}\">{}</a>", cap.at(1).unwrap(), id,
+                            title)
+                },
+                Err(e) => { error = Some(e); whole.to_string() }
+            }
+        });
+        let segs = markdown::segments(&new_chunk);
+        let new_chunk = re_figure.replace_all(&new_chunk,
+                                              |cap: &regex::Captures| {
+            let whole = cap.at(0).unwrap();
+            if error.is_some() || in_code(&segs, cap.pos(0).unwrap().0) {
+                return whole.to_string();
+            }
+            let text = "Figure ".to_string() + &next_number("fig");
+            let caption = cap.at(2).unwrap();
+            match define_label(&mut labels, cap.at(3).unwrap().to_string(),
+                               text.clone(), source) {
+                Ok(id) => {
+                    format!("<div class=\"figure\" id=\"{}\">\n\n\
+                             {}\n\n\
+                             <div class=\"figure_caption\">{}: {}</div>\n\n\
+                             </div>",
+                            id, cap.at(1).unwrap(), text, caption)
+                },
+                Err(e) => { error = Some(e); whole.to_string() }
+            }
+        });
+        let segs = markdown::segments(&new_chunk);
+        let new_chunk = re_caption.replace_all(&new_chunk,
+                                               |cap: &regex::Captures| {
+            let whole = cap.at(0).unwrap();
+            if error.is_some() || in_code(&segs, cap.pos(0).unwrap().0) {
+                return whole.to_string();
+            }
+            let (kind, class) = match cap.at(4).unwrap() {
+                "tbl" => ("tbl", "table_caption"),
+                _ => ("lst", "listing_caption")
+            };
+            let text = cap.at(1).unwrap().to_string() + " " +
+                       &next_number(kind);
+            match define_label(&mut labels, cap.at(3).unwrap().to_string(),
+                               text.clone(), source) {
+                Ok(id) => {
+                    format!("<div class=\"{}\" id=\"{}\">{}: {}</div>",
+                            class, id, text, cap.at(2).unwrap())
+                },
+                Err(e) => { error = Some(e); whole.to_string() }
+            }
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+        chunk.clear();
+        chunk.push_str(&new_chunk);
+    }
+    // Then resolve references, in plain text only.
+    for (chunk, source) in content.chunks.iter_mut()
+                           .zip(content.sources.iter()) {
+        let new_chunk = map_text_segments(&chunk, |text| {
+            re_ref.replace_all(text, |cap: &regex::Captures| {
+                let label = cap.at(1).or(cap.at(2)).unwrap();
+                match labels.get(label) {
+                    Some(l) => format!("[{}](#{})", l.text, l.id),
+                    None => {
+                        if error.is_none() {
+                            error = Some("Reference to undefined label '"
+                                         .to_string() + label + "' in " +
+                                         source + ".");
+                        }
+                        cap.at(0).unwrap().to_string()
+                    }
+                }
+            })
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+        chunk.clear();
+        chunk.push_str(&new_chunk);
+    }
+    Ok(())
+}
+
+// An index term with the places it occurs, as (chapter number, anchor id), the
+// entries it refers to, as (term path, source), and its sub-entries, keyed by
+// their sort order.
+struct IndexEntry {
+    term: String,
+    occurrences: Vec<(String, String)>,
+    see_also: Vec<(String, String)>,
+    subs: BTreeMap<String, IndexEntry>,
+}
+
+fn index_sort_key(term: &str) -> String {
+    term.to_lowercase() + "\u{0}" + term
+}
+
+// The key of an entry given by its path of terms, as in "borrow checker!
+// lifetimes".
+fn index_path_key(path: &str) -> String {
+    path.split('!').map(|t| index_sort_key(t.trim()))
+        .collect::<Vec<String>>().join("\u{1}")
+}
+
+// Gives every entry an anchor, keyed by its path. Top level entries are
+// numbered in order, and sub-entries within their parent.
+fn index_ids(entries: &BTreeMap<String, IndexEntry>, parent_key: &str,
+             parent_id: &str, ids: &mut BTreeMap<String, String>) {
+    for (i, (key, entry)) in entries.iter().enumerate() {
+        let path = if parent_key.is_empty() {
+            key.clone()
+        } else {
+            parent_key.to_string() + "\u{1}" + key
+        };
+        let id = format!("{}_{}", parent_id, i + 1);
+        index_ids(&entry.subs, &path, &id, ids);
+        ids.insert(path, id);
+    }
+}
+
+fn index_entry<'a>(entries: &'a mut BTreeMap<String, IndexEntry>,
+                   path: &[&str]) -> &'a mut IndexEntry {
+    let term = path[0].trim();
+    let entry = entries.entry(index_sort_key(term)).or_insert(IndexEntry {
+        term: term.to_string(),
+        occurrences: Vec::new(),
+        see_also: Vec::new(),
+        subs: BTreeMap::new()
+    });
+    if path.len() == 1 {
+        entry
+    } else {
+        index_entry(&mut entry.subs, &path[1..])
+    }
+}
+
+// Renders entries as a nested CommonMark list. `ids` maps the path of each
+// entry to its anchor.
+fn render_index(entries: &BTreeMap<String, IndexEntry>,
+                ids: &BTreeMap<String, String>, parent_key: &str,
+                indent: usize) -> Result<String, String> {
+    let mut res = String::new();
+    for (key, entry) in entries.iter() {
+        let path = if parent_key.is_empty() {
+            key.clone()
+        } else {
+            parent_key.to_string() + "\u{1}" + key
+        };
+        res = res + &" ".repeat(indent) + "- <a id=\"" + &ids[&path] +
+              "\"></a>" + &entry.term;
+        let links: Vec<String> = entry.occurrences.iter()
+            .map(|&(ref c, ref id)| format!("[{}](#{})", c, id)).collect();
+        if !links.is_empty() {
+            res = res + ": " + &links.join(", ");
+        }
+        let mut see = Vec::new();
+        for &(ref target, ref source) in entry.see_also.iter() {
+            match ids.get(&index_path_key(target)) {
+                Some(id) => {
+                    let shown: Vec<&str> = target.split('!')
+                                           .map(|t| t.trim()).collect();
+                    see.push(format!("[{}](#{})", shown.join(", "), id));
+                },
+                None => {
+                    return Err("Index entry '".to_string() + &entry.term +
+                               "' in " + source + " refers to '" + target +
+                               "', which is not in the index.");
+                }
+            }
+        }
+        if !see.is_empty() {
+            res = res + if links.is_empty() { ": " } else { "; " } +
+                  "*see also* " + &see.join(", ");
+        }
+        match render_index(&entry.subs, ids, &path, indent + 2) {
+            Ok(x) => { res = res + "\n\n" + &x; },
+            Err(e) => { return Err(e); }
+        }
+    }
+    Ok(res)
+}
+
+fn apply_index(content: &mut structure::Content) -> Result<(), String> {
+    let re = regex::Regex::new(
+        r#"\[([^\]]*)\]\{\.index((?:[ \t]+(?:term|see)="[^"]*")*)[ \t]*\}"#)
+        .unwrap();
+    let re_attr = regex::Regex::new(r#"(term|see)="([^"]*)""#).unwrap();
+    let mut entries = BTreeMap::new();
+    let mut index_id = 0;
+    let mut error = None;
+    for ((chunk, source), chapter) in content.chunks.iter_mut()
+                                      .zip(content.sources.iter())
+                                      .zip(content.chapters.iter()) {
+        let new_chunk = map_text_segments(&chunk, |text| {
+            re.replace_all(text, |cap: &regex::Captures| {
+                let shown = cap.at(1).unwrap();
+                let mut term = shown.to_string();
+                let mut see = Vec::new();
+                for attr in re_attr.captures_iter(cap.at(2).unwrap()) {
+                    match attr.at(1).unwrap() {
+                        "term" => { term = attr.at(2).unwrap().to_string(); },
+                        _ => { see.push(attr.at(2).unwrap().to_string()); }
+                    }
+                }
+                let path: Vec<&str> = term.split('!').collect();
+                let see_paths = see.iter().flat_map(|t| t.split('!'));
+                if path.iter().cloned().chain(see_paths)
+                       .any(|t| t.trim().is_empty()) {
+                    if error.is_none() {
+                        error = Some("Index entry '".to_string() +
+                                     cap.at(0).unwrap() + "' in " + source +
+                                     " has no term.");
+                    }
+                    return cap.at(0).unwrap().to_string();
+                }
+                // An empty entry with a "see also" adds no location.
+                let see_only = shown.is_empty() && !see.is_empty();
+                let entry = index_entry(&mut entries, &path);
+                entry.see_also.extend(see.into_iter().map(|t| {
+                    (t, source.clone())
+                }));
+                if see_only {
+                    return String::new();
+                }
+                index_id += 1;
+                let id = format!("kos_idx_{}", index_id);
+                let location = if chapter.is_empty() {
+                    source.clone()
+                } else {
+                    chapter.clone()
+                };
+                entry.occurrences.push((location, id.clone()));
+                format!("<a class=\"index_term\" id=\"{}\"></a>{}", id, shown)
+            })
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+        chunk.clear();
+        chunk.push_str(&new_chunk);
+    }
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let mut ids = BTreeMap::new();
+    index_ids(&entries, "", "kos_index", &mut ids);
+    let list = match render_index(&entries, &ids, "", 0) {
+        Ok(x) => x,
+        Err(e) => { return Err(e); }
+    };
+    let index = "# <a id=\"kos_index\">Index</a>\n\n\
+                 <div class=\"book_index\">\n\n".to_string() + &list +
+                "</div>\n\n";
+    content.push_chunk("<index>", "", index);
+    content.add_toc_entry("Index", "kos_index");
+    Ok(())
+}
+
+impl NativeRule {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            NativeRule::FigureNumbering => "Built-in figure numbering",
+            NativeRule::Abbreviations => "Built-in abbreviations",
+            NativeRule::SmartQuotes => "Built-in smart quotes",
+            NativeRule::CrossReferences => "Built-in cross-references",
+            NativeRule::Index => "Built-in index",
+        }
+    }
+
+    pub fn apply(&self, engine: &mut rules::RulesEngine,
+                 content: &mut structure::Content) -> Result<(), String> {
+        match *self {
+            NativeRule::FigureNumbering => {
+                apply_figure_numbering(engine, content)
+            },
+            NativeRule::Abbreviations => apply_abbreviations(engine, content),
+            NativeRule::SmartQuotes => apply_smart_quotes(content),
+            NativeRule::CrossReferences => apply_cross_references(content),
+            NativeRule::Index => apply_index(content),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json;
+
+    use rules;
+    use structure::Content;
+
+    // Applies a built-in rule set to chunks given as (source, chapter, text),
+    // returning the new chunks and the variables as JSON.
+    fn apply(builtin: &str, chunks: &[(&str, &str, &str)])
+    -> Result<(Vec<String>, json::Json), String> {
+        let rsc = rules::RuleSpecContainer::from_json(
+            &format!(r#"{{"rules": [{{"builtin": "{}"}}]}}"#, builtin))
+            .unwrap();
+        let mut content = Content::new();
+        for &(source, chapter, text) in chunks.iter() {
+            content.push_chunk(source, chapter, text.to_string());
+        }
+        let mut engine = rules::RulesEngine::new();
+        match engine.apply_rules(&rsc, &mut content) {
+            Ok(_) => {
+                let vars = json::Json::from_str(&engine.variables_to_json())
+                           .unwrap();
+                Ok((content.chunks, vars))
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    fn apply_one(builtin: &str, text: &str) -> String {
+        apply(builtin, &[("a.md", "1.1", text)]).unwrap().0.remove(0)
+    }
+
+    #[test]
+    fn unknown_builtin() {
+        assert!(super::lookup("no-such-rule").is_err());
+    }
+
+    #[test]
+    fn admonitions() {
+        assert_eq!(apply_one("admonitions", "WARNING: hot\nsurface\n\nAfter"),
+                   "<div class=\"admonition admonition_warning\">\n\n\
+                    **Warning:** hot\nsurface\n\n\n</div>\n\n\nAfter");
+        // Only at the start of a line, and only in capitals.
+        assert_eq!(apply_one("admonitions", "A NOTE: x\n\nNote: y\n"),
+                   "A NOTE: x\n\nNote: y\n");
+        // Not in code, and the body stops before a fence.
+        assert_eq!(apply_one("admonitions", "```\nNOTE: x\n```\n"),
+                   "```\nNOTE: x\n```\n");
+        assert_eq!(apply_one("admonitions", "TIP: x\n```\ny\n```\n"),
+                   "<div class=\"admonition admonition_tip\">\n\n\
+                    **Tip:** x\n\n\n</div>\n\n```\ny\n```\n");
+    }
+
+    #[test]
+    fn glossary() {
+        let (chunks, vars) = apply("glossary", &[
+            ("a.md", "1.1", "[glossary:api]: Application interface\nText\n")
+        ]).unwrap();
+        assert_eq!(chunks[0], "\nText\n");
+        let (chunks, _) = apply("glossary", &[
+            ("a.md", "1.1", "```\n[glossary:api]: Not a definition\n```\n")
+        ]).unwrap();
+        assert_eq!(chunks[0],
+                   "```\n[glossary:api]: Not a definition\n```\n");
+        assert_eq!(vars.find_path(&["glossary", "api"])
+                       .and_then(|v| v.as_string()),
+                   Some("Application interface"));
+    }
+
+    #[test]
+    fn todo_markers() {
+        let (chunks, vars) = apply("todo-markers", &[
+            ("a.md", "1.1", "Draft. TODO: add a figure\n\n`FIXME: code`\n")
+        ]).unwrap();
+        assert_eq!(chunks[0],
+                   "Draft. <span class=\"todo_marker\">TODO: add a \
+                    figure</span>\n\n`FIXME: code`\n");
+        assert_eq!(vars.find("todo_list").unwrap().to_string(),
+                   r#"["- TODO: add a figure"]"#);
+    }
+
+    #[test]
+    fn figure_numbering() {
+        let (chunks, vars) = apply("figure-numbering", &[
+            ("a.md", "1.1", "![A map](map.png)\n"),
+            ("b.md", "1.2", "Inline ![icon](i.png) stays.\n\n![](b.png)\n")
+        ]).unwrap();
+        assert_eq!(chunks[0],
+                   "<div class=\"figure\" id=\"kos_fig_1\">\n\n\
+                    ![A map](map.png)\n\n\
+                    <div class=\"figure_caption\">Figure 1: A map</div>\n\n\
+                    </div>\n");
+        assert!(chunks[1].starts_with("Inline ![icon](i.png) stays.\n\n\
+                                       <div class=\"figure\" \
+                                       id=\"kos_fig_2\">"));
+        assert_eq!(vars.find("figure_list").unwrap().to_string(),
+                   r#"["Figure 1: A map","Figure 2: "]"#);
+    }
+
+    #[test]
+    fn abbreviations() {
+        let (chunks, vars) = apply("abbreviations", &[
+            ("a.md", "1.1", "Use HTML5, not HTML or `HTML`.\n"),
+            ("b.md", "1.2", "*[HTML]: Hyper \"Text\"\n*[HTML5]: HTML 5\n\
+                             *[e.g.]: for example\nSee e.g. here.\n")
+        ]).unwrap();
+        assert_eq!(chunks[0],
+                   "Use <abbr title=\"HTML 5\">HTML5</abbr>, not \
+                    <abbr title=\"Hyper &quot;Text&quot;\">HTML</abbr> or \
+                    `HTML`.\n");
+        assert_eq!(chunks[1],
+                   "See <abbr title=\"for example\">e.g.</abbr> here.\n");
+        // Abbreviations which cannot be keys are not stored.
+        let stored = vars.find("abbreviations").unwrap().as_object().unwrap();
+        assert_eq!(stored.keys().collect::<Vec<_>>(), vec!["HTML", "HTML5"]);
+    }
+
+    #[test]
+    fn smart_quotes() {
+        assert_eq!(apply_one("smart-quotes",
+                             "\"Hi,\" she said (it's 'fine'). `\"x\"`\n\n\
+                              <a href=\"u\">\"y\"</a>\n"),
+                   "\u{201C}Hi,\u{201D} she said (it\u{2019}s \
+                    \u{2018}fine\u{2019}). `\"x\"`\n\n\
+                    <a href=\"u\">\u{201C}y\u{201D}</a>\n");
+    }
+
+    #[test]
+    fn cross_references() {
+        let (chunks, _) = apply("cross-references", &[
+            ("a.md", "1.1", "See @sec:intro, [[fig:map]] and @tbl:sizes.\n\n\
+                             ## Introduction {#sec:intro} ##\n\n\
+                             ![A map](map.png){#fig:map}\n\n\
+                             Table: Sizes {#tbl:sizes}\n\n\
+                             `@sec:intro` mail@sec:intro\n"),
+            ("b.md", "1.2", "Listing: Code {#lst:code}\n")
+        ]).unwrap();
+        assert_eq!(chunks[0],
+                   "See [Introduction](#kos_ref_sec_intro), \
+                    [Figure 1.1.1](#kos_ref_fig_map) and \
+                    [Table 1.1.1](#kos_ref_tbl_sizes).\n\n\
+                    ## <a id=\"kos_ref_sec_intro\">Introduction</a>\n\n\
+                    <div class=\"figure\" id=\"kos_ref_fig_map\">\n\n\
+                    ![A map](map.png)\n\n\
+                    <div class=\"figure_caption\">Figure 1.1.1: A map</div>\
+                    \n\n</div>\n\n\
+                    <div class=\"table_caption\" id=\"kos_ref_tbl_sizes\">\
+                    Table 1.1.1: Sizes</div>\n\n\
+                    `@sec:intro` mail@sec:intro\n");
+        assert_eq!(chunks[1],
+                   "<div class=\"listing_caption\" id=\"kos_ref_lst_code\">\
+                    Listing 1.2.1: Code</div>\n");
+    }
+
+    #[test]
+    fn cross_reference_errors() {
+        assert!(apply("cross-references", &[("a.md", "1.1", "@fig:none\n")])
+                .is_err());
+        assert!(apply("cross-references", &[
+            ("a.md", "1.1", "# A {#sec:a}\n"),
+            ("b.md", "1.2", "# B {#sec:a}\n")
+        ]).is_err());
+    }
+
+    #[test]
+    fn index() {
+        let (chunks, _) = apply("index", &[
+            ("a.md", "1.1", "[Traits]{.index} and \
+                             [bounds]{.index term=\"traits!bounds\"}.\n\n\
+                             []{.index term=\"generics\" \
+                             see=\"traits!bounds\"}\n")
+        ]).unwrap();
+        assert_eq!(chunks[0],
+                   "<a class=\"index_term\" id=\"kos_idx_1\"></a>Traits and \
+                    <a class=\"index_term\" id=\"kos_idx_2\"></a>bounds.\n\n\
+                    \n");
+        assert_eq!(chunks[1],
+                   "# <a id=\"kos_index\">Index</a>\n\n\
+                    <div class=\"book_index\">\n\n\
+                    - <a id=\"kos_index_1\"></a>generics: *see also* \
+                    [traits, bounds](#kos_index_3_1)\n\n\
+                    - <a id=\"kos_index_2\"></a>Traits: [1.1](#kos_idx_1)\n\n\
+                    - <a id=\"kos_index_3\"></a>traits\n\n  \
+                    - <a id=\"kos_index_3_1\"></a>bounds: [1.1](#kos_idx_2)\
+                    \n\n</div>\n\n");
+    }
+
+    #[test]
+    fn index_errors() {
+        assert!(apply("index", &[("a.md", "1.1", "[x]{.index term=\"a!\"}")])
+                .is_err());
+        assert!(apply("index", &[
+            ("a.md", "1.1", "[x]{.index see=\"nowhere\"}")
+        ]).is_err());
+    }
+}
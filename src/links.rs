@@ -0,0 +1,448 @@
+// Copyright 2016 Dario Domizioli
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use pulldown_cmark;
+use pulldown_cmark::{Event, Tag};
+use rustc_serialize::json;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::prelude::*;
+use std::fs::File;
+use std::net::{IpAddr, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+use regex;
+
+use assets;
+use structure;
+use structure::Content;
+
+/// A link, image or footnote reference which does not point to anything.
+pub struct BrokenLink {
+    pub source: String,
+    pub line: usize,
+    pub target: String,
+    pub reason: String
+}
+
+// What a link points to, to be checked once all ids are known.
+enum Target {
+    Anchor(String),
+    File(String),
+    Footnote(String),
+}
+
+/// Checks the links, images and footnote references of the CommonMark
+/// content of the book: anchors (`#id`) must be the id of an element of the
+/// book, local files and directories must exist (relative to the file the
+/// link is in), and footnotes must be defined. Lines are those of the files as
+/// they were written.
+pub fn check_links(text: &str, opts: pulldown_cmark::Options,
+                   content: &Content) -> Vec<BrokenLink> {
+    let re_id = regex::Regex::new(r#"\sid="([^"]*)""#).unwrap();
+    let mut ids = BTreeSet::new();
+    let mut footnotes = BTreeSet::new();
+    let mut targets = Vec::new();
+    let mut parser = pulldown_cmark::Parser::new_ext(text, opts);
+    while let Some(event) = parser.next() {
+        let offset = parser.get_offset();
+        let source = content.source_at(offset);
+        let target = match event {
+            Event::Html(ref html) | Event::InlineHtml(ref html) => {
+                for cap in re_id.captures_iter(html) {
+                    ids.insert(cap.at(1).unwrap().to_string());
+                }
+                None
+            },
+            Event::Start(Tag::FootnoteDefinition(ref name)) => {
+                footnotes.insert(name.to_string());
+                None
+            },
+            Event::FootnoteReference(ref name) => {
+                Some(Target::Footnote(name.to_string()))
+            },
+            Event::Start(Tag::Link(ref url, _)) |
+            Event::Start(Tag::Image(ref url, _)) => {
+                if url.starts_with('#') {
+                    Some(Target::Anchor(url[1..].to_string()))
+                } else if assets::is_local(url) {
+                    let base = if source.starts_with('<') {
+                        Path::new("")
+                    } else {
+                        Path::new(source).parent().unwrap_or(Path::new(""))
+                    };
+                    Some(Target::File(assets::local_path(url, base)))
+                } else {
+                    None
+                }
+            },
+            _ => None
+        };
+        if let Some(t) = target {
+            targets.push((t, offset));
+        }
+    }
+    targets.into_iter().filter_map(|(target, offset)| {
+        let (target, reason) = match target {
+            Target::Anchor(id) => {
+                if ids.contains(&id) {
+                    return None;
+                }
+                ("#".to_string() + &id, "no element has this id")
+            },
+            Target::File(path) => {
+                // Links to directories are left alone, like other links
+                // which do not name an asset.
+                if Path::new(&path).exists() {
+                    return None;
+                }
+                (path, "no such file")
+            },
+            Target::Footnote(name) => {
+                if footnotes.contains(&name) {
+                    return None;
+                }
+                // Show the label as written, without the prefix which makes
+                // it unique to its file.
+                ("[^".to_string() + &structure::footnote_label(&name) + "]",
+                 "the footnote is not defined")
+            }
+        };
+        let (source, line) = content.location_at(offset);
+        Some(BrokenLink {
+            source: source.to_string(),
+            line: line,
+            target: target,
+            reason: reason.to_string()
+        })
+    }).collect()
+}
+
+/// Checks external links offline, against a cache file of known results and
+/// a local HTTP server standing in for the web.
+pub struct LinkChecker {
+    // The status code of each known URL, and where it redirects to, if
+    // anywhere.
+    cache: BTreeMap<String, (u64, Option<String>)>,
+    cache_file: Option<String>,
+    // The host and port of the stand-in server.
+    server: Option<(String, u16)>,
+    updated: bool
+}
+
+impl LinkChecker {
+    /// Reads the cache file, if any, and checks that the stand-in server, if
+    /// any, is a loopback address such as `http://127.0.0.1:8080`.
+    pub fn new(cache_file: Option<String>, server: Option<String>)
+    -> Result<LinkChecker, String> {
+        let mut cache = BTreeMap::new();
+        if let Some(ref f) = cache_file {
+            match File::open(f) {
+                Ok(mut fread) => {
+                    let mut res = String::new();
+                    match fread.read_to_string(&mut res) {
+                        Ok(_) => (),
+                        Err(_) => {
+                            return Err("Error reading link cache file '"
+                                       .to_string() + f + "'.");
+                        }
+                    }
+                    match LinkChecker::decode_cache(&res) {
+                        Ok(x) => { cache = x; },
+                        Err(e) => { return Err(e); }
+                    }
+                },
+                // A missing cache file is created from the server's answers.
+                Err(_) if server.is_some() => (),
+                Err(_) => {
+                    return Err("Error opening link cache file '".to_string() +
+                               f + "'.");
+                }
+            }
+        }
+        let server = match server {
+            Some(url) => {
+                let re = regex::Regex::new(
+                    r"^http://([^/:\[\]]+|\[[^\]]+\])(?::([0-9]+))?/?$")
+                    .unwrap();
+                let cap = match re.captures(&url) {
+                    Some(x) => x,
+                    None => {
+                        return Err("Link server '".to_string() + &url +
+                                   "' is invalid. Please use a URL such as " +
+                                   "'http://127.0.0.1:8080'.");
+                    }
+                };
+                let host = cap.at(1).unwrap().trim_matches(|c| {
+                    c == '[' || c == ']'
+                }).to_string();
+                let loopback = host == "localhost" ||
+                               host.parse::<IpAddr>().map(|a| {
+                                   a.is_loopback()
+                               }).unwrap_or(false);
+                if !loopback {
+                    return Err("Link server '".to_string() + &url + "' " +
+                               "is not a loopback address. External links " +
+                               "are only checked against a local server.");
+                }
+                let port = match cap.at(2).map_or(Ok(80), |p| p.parse()) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        return Err("Link server '".to_string() + &url +
+                                   "' has an invalid port.");
+                    }
+                };
+                Some((host, port))
+            },
+            None => None
+        };
+        Ok(LinkChecker {
+            cache: cache,
+            cache_file: cache_file,
+            server: server,
+            updated: false
+        })
+    }
+
+    fn decode_cache(js: &str)
+    -> Result<BTreeMap<String, (u64, Option<String>)>, String> {
+        let error = "Error parsing link cache: the top level value must be \
+                     an object mapping URLs to objects with a numeric \
+                     \"status\" and an optional \"location\" string."
+                    .to_string();
+        let root = match json::Json::from_str(js) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Error parsing link cache JSON: {}", e));
+            }
+        };
+        let entries = match root.as_object() {
+            Some(x) => x,
+            None => { return Err(error); }
+        };
+        let mut cache = BTreeMap::new();
+        for (url, entry) in entries.iter() {
+            let status = match entry.find("status").and_then(|s| s.as_u64()) {
+                Some(x) => x,
+                None => { return Err(error); }
+            };
+            let location = entry.find("location").and_then(|l| {
+                l.as_string()
+            }).map(|l| l.to_string());
+            cache.insert(url.clone(), (status, location));
+        }
+        Ok(cache)
+    }
+
+    // Asks the stand-in server about a URL, as a client would ask a proxy.
+    fn query(&self, url: &str) -> Result<(u64, Option<String>), String> {
+        let (ref host, port) = *self.server.as_ref().unwrap();
+        let error = |e: ::std::io::Error| {
+            format!("Error querying link server for '{}': {}", url, e)
+        };
+        let mut stream = match TcpStream::connect((host.as_str(), port)) {
+            Ok(x) => x,
+            Err(e) => { return Err(error(e)); }
+        };
+        let timeout = Some(Duration::from_secs(10));
+        let url_host = url.split('/').nth(2).unwrap_or("");
+        let request = format!("HEAD {} HTTP/1.0\r\nHost: {}\r\n\
+                               User-Agent: kosbook\r\n\r\n", url, url_host);
+        let mut response = String::new();
+        match stream.set_read_timeout(timeout)
+              .and_then(|_| stream.write_all(request.as_bytes()))
+              .and_then(|_| stream.read_to_string(&mut response)) {
+            Ok(_) => (),
+            Err(e) => { return Err(error(e)); }
+        }
+        let mut lines = response.lines();
+        let status = lines.next().and_then(|l| l.split_whitespace().nth(1))
+                     .and_then(|s| s.parse().ok());
+        let status = match status {
+            Some(x) => x,
+            None => {
+                return Err("Invalid response from link server for '"
+                           .to_string() + url + "'.");
+            }
+        };
+        let location = lines.take_while(|l| !l.is_empty()).filter_map(|l| {
+            let mut parts = l.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value))
+                if name.trim().to_lowercase() == "location" => {
+                    Some(value.trim().to_string())
+                },
+                _ => None
+            }
+        }).next();
+        Ok((status, location))
+    }
+
+    /// Checks the external (http and https) links and images of the book,
+    /// reporting those which are unreachable, redirected, or unknown.
+    pub fn check(&mut self, text: &str, opts: pulldown_cmark::Options,
+                 content: &Content) -> Result<Vec<BrokenLink>, String> {
+        let mut reports = Vec::new();
+        let mut parser = pulldown_cmark::Parser::new_ext(text, opts);
+        while let Some(event) = parser.next() {
+            let offset = parser.get_offset();
+            let url = match event {
+                Event::Start(Tag::Link(ref url, _)) |
+                Event::Start(Tag::Image(ref url, _)) => url.to_string(),
+                _ => { continue; }
+            };
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                continue;
+            }
+            // The fragment is never sent to the server.
+            let url = url.split('#').next().unwrap().to_string();
+            if !self.cache.contains_key(&url) && self.server.is_some() {
+                match self.query(&url) {
+                    Ok(x) => {
+                        self.cache.insert(url.clone(), x);
+                        self.updated = true;
+                    },
+                    Err(e) => { return Err(e); }
+                }
+            }
+            let reason = match self.cache.get(&url) {
+                Some(&(status, _)) if status >= 200 && status < 300 => {
+                    continue;
+                },
+                Some(&(status, Some(ref location)))
+                if status >= 300 && status < 400 => {
+                    format!("redirected to '{}' ({})", location, status)
+                },
+                Some(&(status, _)) if status >= 300 && status < 400 => {
+                    format!("redirected ({})", status)
+                },
+                Some(&(status, _)) => format!("unreachable ({})", status),
+                None => "not in the link cache".to_string()
+            };
+            let (source, line) = content.location_at(offset);
+            reports.push(BrokenLink {
+                source: source.to_string(),
+                line: line,
+                target: url,
+                reason: reason
+            });
+        }
+        Ok(reports)
+    }
+
+    /// Writes the results obtained from the server back to the cache file.
+    pub fn save(&self) -> Result<(), String> {
+        let file = match self.cache_file {
+            Some(ref f) if self.updated => f,
+            _ => { return Ok(()); }
+        };
+        let mut entries = BTreeMap::new();
+        for (url, &(status, ref location)) in self.cache.iter() {
+            let mut entry = BTreeMap::new();
+            entry.insert("status".to_string(), json::Json::U64(status));
+            if let Some(ref l) = *location {
+                entry.insert("location".to_string(),
+                             json::Json::String(l.clone()));
+            }
+            entries.insert(url.clone(), json::Json::Object(entry));
+        }
+        let text = format!("{}\n", json::Json::Object(entries).pretty());
+        match File::create(file).and_then(|mut f| {
+            f.write_all(text.as_bytes())
+        }) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                Err(format!("Error writing link cache file '{}': {}", file, e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pulldown_cmark;
+
+    use super::{check_links, LinkChecker};
+    use structure::Content;
+
+    fn broken(chunks: &[(&str, &str)]) -> Vec<(String, usize, String)> {
+        let mut content = Content::new();
+        for &(source, text) in chunks.iter() {
+            content.push_chunk(source, "", text.to_string());
+        }
+        let mut opts = pulldown_cmark::Options::empty();
+        opts.insert(pulldown_cmark::OPTION_ENABLE_TABLES);
+        opts.insert(pulldown_cmark::OPTION_ENABLE_FOOTNOTES);
+        let text = content.to_single_string();
+        check_links(&text, opts, &content).into_iter().map(|l| {
+            (l.source, l.line, l.target)
+        }).collect()
+    }
+
+    #[test]
+    fn anchors() {
+        let res = broken(&[
+            ("<a>", "# <a id=\"top\">Top</a>\n\n[up](#top)"),
+            ("<b>", "Text\n\n[down](#bottom)\n\n<span id=\"x\"></span>[x](#x)")
+        ]);
+        assert_eq!(res, vec![("<b>".to_string(), 3, "#bottom".to_string())]);
+    }
+
+    #[test]
+    fn local_files() {
+        // Generated content is relative to the directory the tool runs in.
+        let res = broken(&[
+            ("<a>", "[manifest](Cargo.toml) [dir](src) ![img](missing.png)")
+        ]);
+        assert_eq!(res, vec![("<a>".to_string(), 1,
+                              "missing.png".to_string())]);
+    }
+
+    #[test]
+    fn footnotes() {
+        let res = broken(&[
+            ("<a>", "A[^kos_fn_1_1_1_a] B[^kos_fn_1_1_1_b]\n\n\
+                     [^kos_fn_1_1_1_a]: Defined.")
+        ]);
+        assert_eq!(res, vec![("<a>".to_string(), 1, "[^b]".to_string())]);
+    }
+
+    #[test]
+    fn cache() {
+        let cache = LinkChecker::decode_cache(r#"{
+            "http://a.com/": {"status": 200},
+            "http://b.com/": {"status": 301, "location": "https://b.com/"}
+        }"#).unwrap();
+        assert_eq!(cache["http://a.com/"], (200, None));
+        assert_eq!(cache["http://b.com/"],
+                   (301, Some("https://b.com/".to_string())));
+        assert!(LinkChecker::decode_cache("[]").is_err());
+        assert!(LinkChecker::decode_cache(r#"{"http://a.com/": {}}"#)
+                .is_err());
+        assert!(LinkChecker::decode_cache(r#"{"http://a.com/": 200}"#)
+                .is_err());
+        assert!(LinkChecker::decode_cache("{").is_err());
+    }
+
+    #[test]
+    fn servers() {
+        let server = |url: &str| LinkChecker::new(None, Some(url.to_string()));
+        assert!(server("http://127.0.0.1:8080").is_ok());
+        assert!(server("http://[::1]/").is_ok());
+        assert!(server("http://example.com").is_err());
+        assert!(server("ftp://localhost").is_err());
+        assert!(LinkChecker::new(Some("no_such_cache.json".to_string()), None)
+                .is_err());
+    }
+}
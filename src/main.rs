@@ -16,39 +16,28 @@ extern crate rustc_serialize;
 extern crate getopts;
 extern crate pulldown_cmark;
 extern crate regex;
+extern crate mlua;
 
 use std::io::prelude::*;
 use std::io::BufWriter;
 use std::fs::File;
+use std::path::Path;
 use std::env;
 
 mod structure;
 mod rules;
+mod builtins;
+mod template;
+mod markdown;
+mod assets;
+mod links;
+mod bibliography;
+mod scripting;
+mod search;
+mod theme;
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
-fn html_prologue(style: &str, title: &str) -> String {
-    return r#"<!DOCTYPE html>
-<html>
-<head>
-    <meta charset="utf-8">
-    <meta name="generator" content="kosbook">
-    <title>"#.to_string() +
-    title +
-    r#"</title>
-    <link rel="stylesheet" type="text/css" href=""# +
-    style +
-    r#"">
-</head>
-<body>
-
-"#;
-}
-
-fn html_epilogue() -> String {
-    return "\n\n</body>\n</html>\n".to_string();
-}
-
 fn main() {
     // Command-line options
     let args: Vec<_> = env::args().collect();
@@ -60,14 +49,51 @@ fn main() {
     opts.optopt("o", "output", 
                 "specify output file (default: ./output.html)",
                 "FILE");
+    opts.optopt("", "out-dir",
+                "write the HTML as index.html into the given directory, together with its stylesheets, scripts, assets and PDF",
+                "DIR");
+    opts.optflag("", "clean",
+                 "remove the files written by previous runs from the output directory given with --out-dir before writing to it");
     opts.optflag("p", "pdf",
                  "also invoke 'wkhtmltopdf' to produce a pdf. Note that wkhtmltopdf must be in your PATH.");
     opts.optopt("r", "rules",
                 "specify the processing rules file (default: ./rules.json)",
                 "FILE");
+    opts.optflag("", "require-rules",
+                 "fail if the rules file cannot be found (by default a missing rules file means no rules)");
+    opts.optflag("", "no-rules",
+                 "do not load or apply any rules");
+    opts.optopt("", "vars",
+                "preload variables from a JSON file before applying rules",
+                "FILE");
+    opts.optmulti("", "var",
+                  "preload a single variable before applying rules (can be repeated)",
+                  "NAME=VALUE");
+    opts.optopt("", "dump-vars",
+                "write all variables to a JSON file after applying rules",
+                "FILE");
+    opts.optflag("", "no-vars-in-code",
+                 "do not substitute variables within code blocks and code spans");
+    opts.optflag("", "explain-rules",
+                 "apply the rules, report what each rule matched and the resulting variables, then exit without writing any output");
+    opts.optflag("", "strict-links",
+                 "fail if any link, image or footnote reference is broken (by default broken links are only reported)");
+    opts.optflag("", "check-external-links",
+                 "check external links against a link cache file and/or a local link server");
+    opts.optopt("", "link-cache",
+                "specify a JSON file of known external link results, updated with those of the link server",
+                "FILE");
+    opts.optopt("", "link-server",
+                "specify a loopback HTTP server standing in for the web when checking external links",
+                "URL");
     opts.optopt("s", "style", 
-                "specify custom path to CSS file (default: style.css)",
+                "specify custom path to CSS file, linked after any stylesheets listed in the structure file (default: style.css if the structure file lists none)",
                 "FILE");
+    opts.optflag("", "standalone",
+                 "write a self-contained HTML file, with stylesheets and scripts inlined and local images and fonts embedded");
+    opts.optopt("t", "theme",
+                "specify a theme directory with templates for the page, cover, TOC, part and chapter headers (default: built-in theme)",
+                "DIR");
     opts.optflag("v", "version", "print version and exit");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -93,7 +119,7 @@ fn main() {
     if let Some(filename) = matches.opt_str("input") {
         structure_file = filename;
     }
-    let structure_json = match File::open(structure_file) {
+    let structure_json = match File::open(&structure_file) {
         Ok(mut fread) => {
             let mut res = String::new();
             match fread.read_to_string(&mut res) {
@@ -117,8 +143,28 @@ fn main() {
             std::process::exit(1);
         }
     };
+    // Files the book is made from, which an output directory must not hold.
+    let mut input_files = structure.input_files();
+    input_files.push(structure_file.clone());
+    input_files.extend(matches.opt_str("theme"));
+    input_files.extend(matches.opt_str("style"));
+    input_files.extend(matches.opt_str("vars"));
+    let theme = match matches.opt_str("theme") {
+        Some(dir) => {
+            println!("Loading theme...");
+            match theme::Theme::load(&dir) {
+                Ok(x) => x,
+                Err(e) => {
+                    println!("error:   {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        None => theme::Theme::default()
+    };
     println!("Generating in-memory content...");
-    let mut content = match structure::Content::from_structure(&structure) {
+    let mut content = match structure::Content::from_structure(&structure,
+                                                               &theme) {
         Ok(x) => x,
         Err(e) => {
             println!("error:   {}", e);
@@ -128,43 +174,131 @@ fn main() {
 
     // Process content through rules
     let mut rule_engine = rules::RulesEngine::new();
+    rule_engine.set_explain(matches.opt_present("explain-rules"));
+    rule_engine.set_skip_code(matches.opt_present("no-vars-in-code"));
 
-    println!("Loading rules file...");
-    let mut pre_rules_file = "rules.json".to_string();
-    if let Some(filename) = matches.opt_str("rules") {
-        pre_rules_file = filename;
+    if let Some(filename) = matches.opt_str("vars") {
+        println!("Loading variables file...");
+        let vars_json = match File::open(filename) {
+            Ok(mut fread) => {
+                let mut res = String::new();
+                match fread.read_to_string(&mut res) {
+                    Ok(_) => (),
+                    Err(_) => {
+                        println!("error:   error reading variables file.");
+                        std::process::exit(1);
+                    }
+                }
+                res
+            },
+            Err(_) => {
+                println!("error:   error opening variables file.");
+                std::process::exit(1);
+            }
+        };
+        match rule_engine.load_variables_json(&vars_json) {
+            Ok(_) => (),
+            Err(e) => {
+                println!("error:   {}", e);
+                std::process::exit(1);
+            }
+        }
     }
-    let pre_rules_json = match File::open(pre_rules_file) {
-        Ok(mut fread) => {
-            let mut res = String::new();
-            match fread.read_to_string(&mut res) {
-                Ok(_) => (),
-                Err(_) => {
-                    println!("error:   error reading rules file.");
-                    std::process::exit(1);
+    for def in matches.opt_strs("var").iter() {
+        match rule_engine.load_variable_definition(def) {
+            Ok(_) => (),
+            Err(e) => {
+                println!("error:   {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if matches.opt_present("no-rules") {
+        println!("Skipping rules...");
+    } else {
+        println!("Loading rules file...");
+        let mut pre_rules_file = "rules.json".to_string();
+        if let Some(filename) = matches.opt_str("rules") {
+            pre_rules_file = filename;
+        }
+        input_files.push(pre_rules_file.clone());
+        let rules = match File::open(&pre_rules_file) {
+            Ok(mut fread) => {
+                let mut res = String::new();
+                match fread.read_to_string(&mut res) {
+                    Ok(_) => (),
+                    Err(_) => {
+                        println!("error:   error reading rules file.");
+                        std::process::exit(1);
+                    }
+                }
+                match rules::RuleSpecContainer::from_json(&res) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        println!("error:   {}", e);
+                        std::process::exit(1);
+                    }
                 }
+            },
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound &&
+                          !matches.opt_present("require-rules") => {
+                println!("warning: rules file '{}' not found, no rules will be applied.",
+                         pre_rules_file);
+                rules::RuleSpecContainer::empty()
+            },
+            Err(_) => {
+                println!("error:   error opening rules file.");
+                std::process::exit(1);
+            }
+        };
+        input_files.extend(rules.script_files());
+        println!("Applying rules...");
+        match rule_engine.apply_rules(&rules, &mut content) {
+            Ok(_) => (),
+            Err(e) => {
+                println!("error:   {}", e);
+                std::process::exit(1);
             }
-            res
-        },
-        Err(_) => {
-            println!("error:   error opening rules file.");
-            std::process::exit(1);
         }
-    };
-    let rules = match rules::RuleSpecContainer::from_json(&pre_rules_json) {
-        Ok(x) => x,
-        Err(e) => {
-            println!("error:   {}", e);
-            std::process::exit(1);
+    }
+
+    if let Some(filename) = matches.opt_str("dump-vars") {
+        println!("Writing variables file...");
+        match File::create(&filename) {
+            Ok(mut f) => {
+                match f.write_all(rule_engine.variables_to_json().as_bytes()) {
+                    Ok(_) => (),
+                    Err(e) => {
+                        println!("error:   {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            },
+            Err(e) => {
+                println!("error:   {}", e);
+                std::process::exit(1);
+            }
         }
-    };
-    println!("Applying rules...");
-    match rule_engine.apply_rules(&rules, &mut content) {
-        Ok(_) => (),
-        Err(e) => {
-            println!("error:   {}", e);
-            std::process::exit(1);
+    }
+
+    if matches.opt_present("explain-rules") {
+        for report in rule_engine.reports().iter() {
+            println!("\nRule '{}':", report.name);
+            if report.matches.is_empty() {
+                println!("    no matches");
+            }
+            for &(ref source, count) in report.matches.iter() {
+                println!("    {}: {} match(es)", source, count);
+            }
+            for &(ref before, ref after) in report.samples.iter() {
+                println!("    sample: \"{}\"\n         -> \"{}\"",
+                         before, after);
+            }
         }
+        println!("\nVariables after applying rules:");
+        print!("{}", rule_engine.describe_variables());
+        return;
     }
 
     // Substitute variables
@@ -177,6 +311,15 @@ fn main() {
         }
     }
     
+    println!("Anchoring headings and building TOC...");
+    match content.finish(&structure, &theme) {
+        Ok(_) => (),
+        Err(e) => {
+            println!("error:   {}", e);
+            std::process::exit(1);
+        }
+    }
+
     // Collate all processed content
     println!("Collating...");
     let collected_string = content.to_single_string();
@@ -186,40 +329,173 @@ fn main() {
     let mut opts = pulldown_cmark::Options::empty();
     opts.insert(pulldown_cmark::OPTION_ENABLE_TABLES);
     opts.insert(pulldown_cmark::OPTION_ENABLE_FOOTNOTES);
-    let mut gen_html = String::with_capacity(collected_string.len() * 3 / 2);
-    let p = pulldown_cmark::Parser::new_ext(&collected_string, opts);
-    pulldown_cmark::html::push_html(&mut gen_html, p);
-
-    // Write output html, wrapped in prologue and epilogue.
-    println!("Writing output HTML file...");
     let mut output_file = "output.html".to_string();
     if let Some(filename) = matches.opt_str("output") {
         output_file = filename;
     }
-    let mut style_file = "style.css".to_string();
-    if let Some(filename) = matches.opt_str("style") {
-        style_file = filename;
-    }
-    match File::create(&output_file) {
-        Ok(f) => {
-            let mut writer = BufWriter::new(f);
-            match writer.write(
-                html_prologue(
-                    &style_file, structure.get_title()).as_bytes()) {
+    let out_dir = matches.opt_str("out-dir");
+    match out_dir {
+        Some(ref dir) => {
+            if matches.opt_present("output") {
+                println!("error:   --output and --out-dir cannot be used together.");
+                std::process::exit(1);
+            }
+            match assets::prepare_out_dir(dir, matches.opt_present("clean"),
+                                          &input_files) {
                 Ok(_) => (),
                 Err(e) => {
                     println!("error:   {}", e);
                     std::process::exit(1);
                 }
-            };
-            match writer.write(gen_html.as_bytes()) {
-                Ok(_) => (),
+            }
+            output_file = Path::new(dir).join("index.html")
+                          .to_string_lossy().into_owned();
+        },
+        None => {
+            if matches.opt_present("clean") {
+                println!("error:   --clean can only be used with --out-dir.");
+                std::process::exit(1);
+            }
+        }
+    }
+    println!("Checking links...");
+    let broken = links::check_links(&collected_string, opts, &content);
+    for link in broken.iter() {
+        println!("warning: broken link '{}' in {}, line {}: {}.",
+                 link.target, link.source, link.line, link.reason);
+    }
+    let mut broken_count = broken.len();
+    if matches.opt_present("check-external-links") {
+        if !matches.opt_present("link-cache") &&
+           !matches.opt_present("link-server") {
+            println!("error:   --check-external-links needs --link-cache or --link-server.");
+            std::process::exit(1);
+        }
+        println!("Checking external links...");
+        let mut checker = match links::LinkChecker::new(
+                matches.opt_str("link-cache"), matches.opt_str("link-server")) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("error:   {}", e);
+                std::process::exit(1);
+            }
+        };
+        let external = match checker.check(&collected_string, opts, &content) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("error:   {}", e);
+                std::process::exit(1);
+            }
+        };
+        for link in external.iter() {
+            println!("warning: external link '{}' in {}, line {}: {}.",
+                     link.target, link.source, link.line, link.reason);
+        }
+        broken_count += external.len();
+        match checker.save() {
+            Ok(_) => (),
+            Err(e) => {
+                println!("error:   {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if broken_count > 0 && matches.opt_present("strict-links") {
+        println!("error:   {} broken link(s) found.", broken_count);
+        std::process::exit(1);
+    }
+    let standalone = matches.opt_present("standalone");
+    let mut pipeline = assets::AssetPipeline::new(&output_file, standalone,
+                                                  out_dir.is_some());
+    for file in input_files.iter() {
+        pipeline.add_input(file);
+    }
+    if matches.opt_present("pdf") {
+        pipeline.add_output(&(output_file.trim_end_matches(".html")
+                              .to_string() + ".pdf"));
+    }
+    let events = match pipeline.process(&collected_string, opts, &content) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("error:   {}", e);
+            std::process::exit(1);
+        }
+    };
+    let search_index = if structure.search() {
+        println!("Building search index...");
+        Some(search::search_index(&events))
+    } else {
+        None
+    };
+    let mut gen_html = String::with_capacity(collected_string.len() * 3 / 2);
+    pulldown_cmark::html::push_html(&mut gen_html, events.into_iter());
+
+    // Write output html, wrapped in the page template.
+    println!("Writing output HTML file...");
+    let mut page_vars = match structure.template_values() {
+        Ok(x) => x,
+        Err(e) => {
+            println!("error:   {}", e);
+            std::process::exit(1);
+        }
+    };
+    match structure.page_values(matches.opt_str("style"), &mut pipeline) {
+        Ok(values) => { page_vars.extend(values); },
+        Err(e) => {
+            println!("error:   {}", e);
+            std::process::exit(1);
+        }
+    }
+    let mut search_box = String::new();
+    if let Some(index) = search_index {
+        let mut scripts = match page_vars.get("scripts") {
+            Some(&template::Value::Str(ref s)) => s.clone(),
+            _ => String::new()
+        };
+        for &(name, js) in [("search_index.js", index.as_str()),
+                            ("search.js", search::SEARCH_JS)].iter() {
+            match pipeline.generated_script(name, js) {
+                Ok(x) => { scripts = scripts + &x; },
                 Err(e) => {
                     println!("error:   {}", e);
                     std::process::exit(1);
                 }
-            };
-            match writer.write(html_epilogue().as_bytes()) {
+            }
+        }
+        page_vars.insert("scripts".to_string(), template::Value::Str(scripts));
+        search_box = match theme.render("search.html", &page_vars) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("error:   {}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+    page_vars.insert("search".to_string(), template::Value::Str(search_box));
+    page_vars.insert("content".to_string(), template::Value::Str(gen_html));
+    let page = match theme.render("page.html", &page_vars) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("error:   {}", e);
+            std::process::exit(1);
+        }
+    };
+    let page = if standalone {
+        println!("Embedding assets...");
+        match assets::embed_html(&page) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("error:   {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        page
+    };
+    match File::create(&output_file) {
+        Ok(f) => {
+            let mut writer = BufWriter::new(f);
+            match writer.write(page.as_bytes()) {
                 Ok(_) => (),
                 Err(e) => {
                     println!("error:   {}", e);
@@ -232,6 +508,13 @@ fn main() {
             std::process::exit(1);
         }
     }
+    match pipeline.prune() {
+        Ok(_) => (),
+        Err(e) => {
+            println!("error:   {}", e);
+            std::process::exit(1);
+        }
+    }
 
     // Finally do the PDF conversion if required
     if matches.opt_present("pdf") {
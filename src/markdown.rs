@@ -0,0 +1,359 @@
+// Copyright 2016 Dario Domizioli
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use pulldown_cmark;
+use pulldown_cmark::{Event, Tag};
+
+use std::borrow::Cow;
+use regex;
+
+/// What a segment of CommonMark source is.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SegmentKind {
+    Text,
+    // Fenced and indented code blocks, and inline code spans.
+    Code,
+    // HTML tags and comments, and template tags.
+    Markup,
+}
+
+fn push_segment(segs: &mut Vec<(SegmentKind, usize, usize)>,
+                kind: SegmentKind, start: usize, end: usize) {
+    if start >= end {
+        return;
+    }
+    if let Some(last) = segs.last_mut() {
+        if last.0 == kind && last.2 == start {
+            last.2 = end;
+            return;
+        }
+    }
+    segs.push((kind, start, end));
+}
+
+/// Splits a chunk of CommonMark source into byte ranges of plain text, code
+/// and markup, using the code and HTML zones found by the CommonMark parser.
+/// Template tags outside code are markup too.
+pub fn segments(chunk: &str) -> Vec<(SegmentKind, usize, usize)> {
+    let zones = zones(chunk);
+    let re_tag = regex::Regex::new(r"\{\{.*?\}\}|\{%.*?%\}").unwrap();
+    let mut ranges: Vec<(SegmentKind, usize, usize)> = zones.code.iter()
+        .map(|&(s, e)| (SegmentKind::Code, s, e))
+        .chain(zones.html.iter().map(|&(s, e)| (SegmentKind::Markup, s, e)))
+        .collect();
+    for (s, e) in re_tag.find_iter(chunk) {
+        if !zones.code.iter().any(|&(cs, ce)| s < ce && cs < e) {
+            ranges.push((SegmentKind::Markup, s, e));
+        }
+    }
+    ranges.sort_by_key(|&(_, s, _)| s);
+    let mut segs = Vec::new();
+    let mut pos = 0;
+    for (kind, start, end) in ranges.into_iter() {
+        // Ranges may overlap, as a template tag within an HTML tag does.
+        let start = if start > pos { start } else { pos };
+        push_segment(&mut segs, SegmentKind::Text, pos, start);
+        push_segment(&mut segs, kind, start, end);
+        if end > pos {
+            pos = end;
+        }
+    }
+    push_segment(&mut segs, SegmentKind::Text, pos, chunk.len());
+    segs
+}
+
+/// Calls `f` on every run of plain text in a CommonMark chunk and copies
+/// everything else (code and markup) through untouched.
+pub fn map_text_segments<F>(chunk: &str, mut f: F) -> String
+    where F: FnMut(&str) -> String {
+    let mut out = String::with_capacity(chunk.len());
+    for (kind, start, end) in segments(chunk).into_iter() {
+        if kind == SegmentKind::Text {
+            out.push_str(&f(&chunk[start..end]));
+        } else {
+            out.push_str(&chunk[start..end]);
+        }
+    }
+    out
+}
+
+/// Byte ranges of a chunk which the CommonMark parser considers code (code
+/// blocks including their fences, and code spans including their backticks)
+/// or HTML (blocks, inline tags and comments).
+pub struct Zones {
+    pub code: Vec<(usize, usize)>,
+    pub html: Vec<(usize, usize)>
+}
+
+/// The range of `text` within `source`, if it is a slice of it.
+pub fn source_range(source: &str, text: &str) -> Option<(usize, usize)> {
+    let base = source.as_ptr() as usize;
+    let start = text.as_ptr() as usize;
+    if start >= base && start + text.len() <= base + source.len() {
+        Some((start - base, start - base + text.len()))
+    } else {
+        None
+    }
+}
+
+/// Sorts `terms` longest first, so that "HTML5" wins over "HTML", and builds
+/// a regex alternation with one group per term, in that order. A term which
+/// starts or ends with a word character must start or end on a word
+/// boundary; others, such as "C++" or "e.g.", cannot.
+pub fn word_bounded_alternation(terms: &mut Vec<&str>) -> String {
+    terms.sort_by(|a, b| b.len().cmp(&a.len()));
+    let is_word = |c: Option<char>| {
+        c.map_or(false, |c| c.is_alphanumeric() || c == '_')
+    };
+    let alternatives: Vec<String> = terms.iter().map(|t| {
+        (if is_word(t.chars().next()) { r"\b(" } else { "(" }).to_string() +
+        &regex::quote(t) +
+        (if is_word(t.chars().last()) { r")\b" } else { ")" })
+    }).collect();
+    format!("(?:{})", alternatives.join("|"))
+}
+
+// The start of the line holding the byte at `pos`, and its end after the
+// line break.
+fn line_start(chunk: &str, pos: usize) -> usize {
+    chunk.as_bytes()[..pos].iter().rposition(|&b| b == b'\n')
+         .map_or(0, |i| i + 1)
+}
+
+fn line_end(chunk: &str, pos: usize) -> usize {
+    chunk.as_bytes()[pos..].iter().position(|&b| b == b'\n')
+         .map_or(chunk.len(), |i| pos + i + 1)
+}
+
+/// Finds the code and HTML zones of a chunk using the CommonMark parser.
+pub fn zones(chunk: &str) -> Zones {
+    let mut opts = pulldown_cmark::Options::empty();
+    opts.insert(pulldown_cmark::OPTION_ENABLE_TABLES);
+    opts.insert(pulldown_cmark::OPTION_ENABLE_FOOTNOTES);
+    let mut res = Zones { code: Vec::new(), html: Vec::new() };
+    // The start of the code block being parsed, if any.
+    let mut block: Option<usize> = None;
+    // The range covered by the code span being parsed, if any.
+    let mut code: Option<Option<(usize, usize)>> = None;
+    let mut parser = pulldown_cmark::Parser::new_ext(chunk, opts);
+    while let Some(event) = parser.next() {
+        // The parser is past the opening line of a code block when it starts
+        // it, and past the closing line when it ends it.
+        let last = if parser.get_offset() > 0 {
+            parser.get_offset() - 1
+        } else {
+            0
+        };
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => {
+                block = Some(line_start(chunk, last));
+            },
+            Event::End(Tag::CodeBlock(_)) => {
+                if let Some(start) = block.take() {
+                    res.code.push((start, line_end(chunk, last)));
+                }
+            },
+            Event::Start(Tag::Code) => {
+                code = Some(None);
+            },
+            Event::End(Tag::Code) => {
+                // Widened to the backticks and the spaces inside them.
+                if let Some(Some((mut start, mut end))) = code {
+                    let bytes = chunk.as_bytes();
+                    while start > 0 && bytes[start - 1] == b' ' {
+                        start -= 1;
+                    }
+                    while start > 0 && bytes[start - 1] == b'`' {
+                        start -= 1;
+                    }
+                    while end < bytes.len() && bytes[end] == b' ' {
+                        end += 1;
+                    }
+                    while end < bytes.len() && bytes[end] == b'`' {
+                        end += 1;
+                    }
+                    res.code.push((start, end));
+                }
+                code = None;
+            },
+            Event::Text(Cow::Borrowed(text)) => {
+                if let (Some(current), Some((start, end))) =
+                       (code, source_range(chunk, text)) {
+                    code = Some(Some(match current {
+                        Some((s, _)) => (s, end),
+                        None => (start, end)
+                    }));
+                }
+            },
+            Event::Html(Cow::Borrowed(text)) |
+            Event::InlineHtml(Cow::Borrowed(text)) => {
+                if let Some(range) = source_range(chunk, text) {
+                    res.html.push(range);
+                }
+            },
+            _ => ()
+        }
+    }
+    res
+}
+
+// The end of the title on the line of an ATX heading, before the optional
+// closing sequence of hashes and any trailing spaces.
+fn atx_title_end(line: &str) -> usize {
+    let trimmed = line.trim_end();
+    let hashes = trimmed.trim_end_matches('#');
+    if hashes.len() == trimmed.len() {
+        return trimmed.len();
+    }
+    if hashes.is_empty() {
+        return 0;
+    }
+    if hashes.ends_with(' ') || hashes.ends_with('\t') {
+        hashes.trim_end().len()
+    } else {
+        trimmed.len()
+    }
+}
+
+/// Finds the headings of a chunk using the CommonMark parser, as (level,
+/// start, end) where start and end are the byte range of the title in the
+/// chunk. ATX and setext headings are found wherever the parser sees them,
+/// including in block quotes and list items.
+pub fn headings(chunk: &str) -> Vec<(usize, usize, usize)> {
+    let mut opts = pulldown_cmark::Options::empty();
+    opts.insert(pulldown_cmark::OPTION_ENABLE_TABLES);
+    opts.insert(pulldown_cmark::OPTION_ENABLE_FOOTNOTES);
+    let mut res = Vec::new();
+    // The start of the title of the heading being parsed, if any.
+    let mut title: Option<usize> = None;
+    let mut parser = pulldown_cmark::Parser::new_ext(chunk, opts);
+    while let Some(event) = parser.next() {
+        // The parser is at the start of the title when it starts a heading,
+        // and past its last line (the underline of a setext heading) when it
+        // ends it.
+        let offset = parser.get_offset();
+        match event {
+            Event::Start(Tag::Header(_)) => {
+                title = Some(offset);
+            },
+            Event::End(Tag::Header(level)) => {
+                let start = match title.take() {
+                    Some(x) => x,
+                    None => { continue; }
+                };
+                let last = line_start(chunk, if offset > 0 {
+                    offset - 1
+                } else {
+                    0
+                });
+                let end = if last <= start {
+                    // ATX: the title is on the last line.
+                    let line_end = match chunk[start..].find('\n') {
+                        Some(i) => start + i,
+                        None => chunk.len()
+                    };
+                    start + atx_title_end(&chunk[start..line_end])
+                } else {
+                    // Setext: the title ends before the underline.
+                    start + chunk[start..last].trim_end().len()
+                };
+                if start < end {
+                    res.push((level as usize, start, end));
+                }
+            },
+            _ => ()
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{headings, map_text_segments, segments, zones, SegmentKind};
+
+    fn split(chunk: &str) -> Vec<(&'static str, &str)> {
+        segments(chunk).into_iter().map(|(kind, start, end)| {
+            let kind = match kind {
+                SegmentKind::Text => "text",
+                SegmentKind::Code => "code",
+                SegmentKind::Markup => "markup"
+            };
+            (kind, &chunk[start..end])
+        }).collect()
+    }
+
+    #[test]
+    fn inline_segments() {
+        assert_eq!(split("A `b {{ c }}` and {{ d }} <em>e</em> 2 < 3."),
+                   vec![("text", "A "), ("code", "`b {{ c }}`"),
+                        ("text", " and "), ("markup", "{{ d }}"),
+                        ("text", " "), ("markup", "<em>"), ("text", "e"),
+                        ("markup", "</em>"), ("text", " 2 < 3.")]);
+        assert_eq!(split("x `` a ` b `` y"),
+                   vec![("text", "x "), ("code", "`` a ` b ``"),
+                        ("text", " y")]);
+    }
+
+    #[test]
+    fn block_segments() {
+        let chunk = "Intro\n\n```rust\nlet x = 1;\n```\n\n    indented\n\n\
+                     > ```\n> q\n> ```\n\n- item\n\n      in list\n\n\
+                     <div>\nhtml\n</div>\n";
+        assert_eq!(split(chunk),
+                   vec![("text", "Intro\n\n"),
+                        ("code", "```rust\nlet x = 1;\n```\n"),
+                        ("text", "\n"), ("code", "    indented\n"),
+                        ("text", "\n"), ("code", "> ```\n> q\n> ```\n"),
+                        ("text", "\n- item\n\n"),
+                        ("code", "      in list\n"), ("text", "\n"),
+                        ("markup", "<div>\nhtml\n</div>\n")]);
+        // An empty or unclosed fence is still code.
+        assert_eq!(split("```\n```\n\n```\nopen\n"),
+                   vec![("code", "```\n```\n"), ("text", "\n"),
+                        ("code", "```\nopen\n")]);
+    }
+
+    #[test]
+    fn not_code() {
+        // Four spaces continuing a paragraph are not an indented block.
+        assert_eq!(split("para\n    more\n"),
+                   vec![("text", "para\n    more\n")]);
+        assert_eq!(split("a ` b"), vec![("text", "a ` b")]);
+    }
+
+    #[test]
+    fn zones_of_chunk() {
+        let chunk = "a `b` <!-- c -->\n\n```\nd\n```\n";
+        let z = zones(chunk);
+        assert_eq!(z.code, vec![(2, 5), (18, 28)]);
+        assert_eq!(z.html, vec![(6, 16)]);
+    }
+
+    #[test]
+    fn headings_of_chunk() {
+        let chunk = "# A #\n\nB\n---\n\n> ## C\n\n- ### D ##x\n\n\
+                     ```\n# E\n```\n\n#\n";
+        let titles: Vec<(usize, &str)> = headings(chunk).into_iter()
+            .map(|(level, start, end)| (level, &chunk[start..end]))
+            .collect();
+        assert_eq!(titles, vec![(1, "A"), (2, "B"), (2, "C"), (3, "D ##x")]);
+    }
+
+    #[test]
+    fn map_text() {
+        let res = map_text_segments("one `one` <b title=\"one\">one</b>",
+                                    |t| t.replace("one", "1"));
+        assert_eq!(res, "1 `one` <b title=\"one\">1</b>");
+    }
+}
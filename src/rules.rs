@@ -13,11 +13,16 @@
 // limitations under the License.
 
 use rustc_serialize::json;
+use rustc_serialize::Decodable;
 
 use std::collections::BTreeMap;
 use regex;
 
+use builtins;
+use markdown;
+use scripting;
 use structure;
+use template;
 
 #[derive(Clone, PartialEq, RustcDecodable, RustcEncodable)]
 pub enum StorageAction {
@@ -25,6 +30,8 @@ pub enum StorageAction {
     StoreVector,
     StoreMapSingle,
     StoreMapVector,
+    Increment,
+    StoreCounter,
 }
 
 #[derive(Clone, PartialEq, RustcDecodable, RustcEncodable)]
@@ -32,7 +39,17 @@ pub struct StorageSpec {
     action: StorageAction,
     replace: String,
     variable: String,
-    key: String
+    key: String,
+    scope: Option<String>
+}
+
+/// When a counter restarts from zero.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CounterScope {
+    // Never; the counter runs through the whole book.
+    Global,
+    // Whenever it is used in a different chapter than the last time.
+    Chapter,
 }
 
 #[derive(Clone, PartialEq, RustcDecodable, RustcEncodable)]
@@ -40,18 +57,121 @@ pub struct RuleSpec {
     name: String,
     regex: String,
     replace: String,
-    storage: Vec<StorageSpec>
+    storage: Vec<StorageSpec>,
+    context: Option<String>,
+    script: Option<String>
+}
+
+/// Which parts of the CommonMark content a rule can match.
+#[derive(Clone, Copy, PartialEq)]
+enum RuleContext {
+    Anywhere,
+    // Anything but code and HTML.
+    TextOnly,
+    // Within a single code block or code span.
+    CodeOnly,
+}
+
+impl RuleContext {
+    fn accepts(&self, zones: &markdown::Zones, start: usize, end: usize)
+    -> bool {
+        let overlaps = |&(s, e): &(usize, usize)| start < e && s < end;
+        match *self {
+            RuleContext::Anywhere => true,
+            RuleContext::TextOnly => {
+                !zones.code.iter().any(|r| overlaps(r)) &&
+                !zones.html.iter().any(|r| overlaps(r))
+            },
+            RuleContext::CodeOnly => {
+                zones.code.iter().any(|&(s, e)| s <= start && end <= e)
+            },
+        }
+    }
+}
+
+impl StorageSpec {
+    pub fn new(action: StorageAction, replace: &str, variable: &str,
+               key: &str) -> StorageSpec {
+        StorageSpec {
+            action: action,
+            replace: replace.to_string(),
+            variable: variable.to_string(),
+            key: key.to_string(),
+            scope: None
+        }
+    }
+
+    fn counter_scope(&self) -> Result<CounterScope, String> {
+        match self.scope.as_ref().map(|c| c.as_str()) {
+            None | Some("global") => Ok(CounterScope::Global),
+            Some("chapter") => Ok(CounterScope::Chapter),
+            Some(c) => {
+                Err("Scope '".to_string() + c + "' for variable '" +
+                    &self.variable + "' is invalid. Please use 'global' or " +
+                    "'chapter'.")
+            }
+        }
+    }
+}
+
+impl RuleSpec {
+    pub fn new(name: &str, regex: &str, replace: &str,
+               storage: Vec<StorageSpec>, context: &str) -> RuleSpec {
+        RuleSpec {
+            name: name.to_string(),
+            regex: regex.to_string(),
+            replace: replace.to_string(),
+            storage: storage,
+            context: Some(context.to_string()),
+            script: None
+        }
+    }
+
+    fn rule_context(&self) -> Result<RuleContext, String> {
+        match self.context.as_ref().map(|c| c.as_str()) {
+            None | Some("anywhere") => Ok(RuleContext::Anywhere),
+            Some("text-only") => Ok(RuleContext::TextOnly),
+            Some("code-only") => Ok(RuleContext::CodeOnly),
+            Some(c) => {
+                Err("Context '".to_string() + c + "' in rule '" + &self.name +
+                    "' is invalid. Please use 'anywhere', 'text-only' or " +
+                    "'code-only'.")
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Rule {
+    Spec(RuleSpec),
+    Native(builtins::NativeRule),
 }
 
-#[derive(Clone, PartialEq, RustcDecodable, RustcEncodable)]
 pub struct RuleSpecContainer {
-    rules: Vec<RuleSpec>
+    rules: Vec<Rule>,
+    scripts: Option<scripting::ScriptHost>
 }
 
 impl RuleSpecContainer {
+    pub fn empty() -> RuleSpecContainer {
+        RuleSpecContainer {
+            rules: Vec::new(),
+            scripts: None
+        }
+    }
+
+    /// The script files of the rules file, if any.
+    pub fn script_files(&self) -> Vec<String> {
+        self.scripts.as_ref().map_or(Vec::new(), |s| s.files().clone())
+    }
+
     fn validate_rules(rsc: &RuleSpecContainer) -> Result<(), String> {
         let valid_names = regex::Regex::new("^[_0-9a-zA-Z]+$").unwrap();
-        for r in rsc.rules.iter() {
+        for rule in rsc.rules.iter() {
+            let r = match *rule {
+                Rule::Spec(ref r) => r,
+                Rule::Native(_) => { continue; }
+            };
             match regex::Regex::new(&r.regex) {
                 Ok(_) => (),
                 Err(_) => {
@@ -59,7 +179,24 @@ impl RuleSpecContainer {
                                "' in rule '" + &r.name + "' is invalid.");
                 }
             }
+            match r.rule_context() {
+                Ok(_) => (),
+                Err(e) => { return Err(e); }
+            }
+            if let Some(ref f) = r.script {
+                if !rsc.scripts.as_ref().map_or(false, |s| s.has_function(f)) {
+                    return Err("Script function '".to_string() + f +
+                               "' in rule '" + &r.name + "' is not defined " +
+                               "in any script file.");
+                }
+            }
             for s in r.storage.iter() {
+                match s.counter_scope() {
+                    Ok(_) => (),
+                    Err(e) => {
+                        return Err(e + " (in rule '" + &r.name + "')");
+                    }
+                }
                 if !valid_names.is_match(&s.variable) {
                     return Err("Variable name '".to_string() + &s.variable +
                                "' in rule '" + &r.name + "'is invalid. " +
@@ -71,14 +208,63 @@ impl RuleSpecContainer {
         Ok(())
     }
 
+    fn decode_rule(js: &json::Json) -> Result<Vec<Rule>, String> {
+        // An entry of the form { "builtin": "name" } selects one of the
+        // built-in rules; anything else must be a full rule spec.
+        if let Some(builtin) = js.find("builtin") {
+            return match builtin.as_string() {
+                Some(name) => builtins::lookup(name),
+                None => Err("The \"builtin\" field of a rule must be a \
+                             string.".to_string())
+            };
+        }
+        let mut decoder = json::Decoder::new(js.clone());
+        match RuleSpec::decode(&mut decoder) {
+            Ok(r) => Ok(vec![Rule::Spec(r)]),
+            Err(e) => Err(format!("Error parsing rules JSON: {}", e))
+        }
+    }
+
     pub fn from_json(js: &str) -> Result<RuleSpecContainer, String> {
-        let rsc = match json::decode::<RuleSpecContainer>(js) {
+        let root = match json::Json::from_str(js) {
             Ok(x) => x,
             Err(e) => {
                 return Err(
                     (format!("Error parsing rules JSON: {}", e)).to_string());
             }
         };
+        let entries = match root.find("rules").and_then(|r| r.as_array()) {
+            Some(x) => x,
+            None => {
+                return Err("Error parsing rules JSON: the top level object \
+                            must contain a \"rules\" array.".to_string());
+            }
+        };
+        let mut rsc = RuleSpecContainer::empty();
+        if let Some(scripts) = root.find("scripts") {
+            let files: Option<Vec<String>> = scripts.as_array().and_then(|a| {
+                a.iter().map(|f| f.as_string().map(|x| x.to_string()))
+                 .collect()
+            });
+            let files = match files {
+                Some(x) => x,
+                None => {
+                    return Err("Error parsing rules JSON: the \"scripts\" \
+                                field must be an array of strings."
+                               .to_string());
+                }
+            };
+            rsc.scripts = match scripting::ScriptHost::load(&files) {
+                Ok(x) => Some(x),
+                Err(e) => { return Err(e); }
+            };
+        }
+        for entry in entries.iter() {
+            match RuleSpecContainer::decode_rule(entry) {
+                Ok(mut x) => { rsc.rules.append(&mut x); },
+                Err(e) => { return Err(e); }
+            }
+        }
         match RuleSpecContainer::validate_rules(&rsc) {
             Ok(_) => (),
             Err(e) => { return Err(e); }
@@ -88,97 +274,503 @@ impl RuleSpecContainer {
 }
 
 
-struct VarVariant {
-    single:     String,
-    vector:     Vec<String>,
-    map_single: BTreeMap<String, String>,
-    map_vector: BTreeMap<String, Vec<String>>,
+/// A storage variable. The first write to a variable (or its preloading)
+/// determines its type, and later writes must use a matching action.
+#[derive(Clone, PartialEq)]
+enum Variable {
+    Single(String),
+    Vector(Vec<String>),
+    MapSingle(BTreeMap<String, String>),
+    MapVector(BTreeMap<String, Vec<String>>),
+    // The current value, and for chapter counters the chapter it counts in.
+    Counter(u64, Option<String>),
+}
+
+// Chapter counters are shown with the full number of their chapter, as in
+// "1.3.2" for the second item of chapter 1.3, so that they cannot collide
+// across parts.
+fn counter_text(value: u64, chapter: &Option<String>) -> String {
+    match *chapter {
+        Some(ref c) if !c.is_empty() => format!("{}.{}", c, value),
+        _ => format!("{}", value)
+    }
+}
+
+// Replaces `$counter` and `${counter}` in a "replace" string with the given
+// counter value, leaving `$$` escapes for the regex expansion.
+fn expand_counter(text: &str, counter: &Option<String>) -> String {
+    let counter = match *counter {
+        Some(ref c) => c,
+        None => { return text.to_string(); }
+    };
+    let mut res = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('$') {
+        res.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        if after.starts_with('$') {
+            res.push_str("$$");
+            rest = &after[1..];
+        } else if after.starts_with("{counter}") {
+            res.push_str(counter);
+            rest = &after[9..];
+        } else if after.starts_with("counter") &&
+                  !after[7..].chars().next().map_or(false, |c| {
+                      c.is_alphanumeric() || c == '_'
+                  }) {
+            res.push_str(counter);
+            rest = &after[7..];
+        } else {
+            res.push('$');
+            rest = after;
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+impl Variable {
+    fn type_name(&self) -> &'static str {
+        match *self {
+            Variable::Single(_) => "single",
+            Variable::Vector(_) => "vector",
+            Variable::MapSingle(_) => "map-of-single",
+            Variable::MapVector(_) => "map-of-vector",
+            Variable::Counter(_, _) => "counter",
+        }
+    }
+
+    fn to_value(&self) -> template::Value {
+        fn string_list(vs: &Vec<String>) -> template::Value {
+            template::Value::List(vs.iter().map(|v| {
+                template::Value::Str(v.clone())
+            }).collect())
+        }
+        match *self {
+            Variable::Single(ref v) => template::Value::Str(v.clone()),
+            Variable::Vector(ref vs) => string_list(vs),
+            Variable::MapSingle(ref m) => {
+                template::Value::Map(m.iter().map(|(k, v)| {
+                    (k.clone(), template::Value::Str(v.clone()))
+                }).collect())
+            },
+            Variable::MapVector(ref m) => {
+                template::Value::Map(m.iter().map(|(k, vs)| {
+                    (k.clone(), string_list(vs))
+                }).collect())
+            },
+            Variable::Counter(n, ref c) => {
+                template::Value::Str(counter_text(n, c))
+            },
+        }
+    }
+
+    fn to_json(&self) -> json::Json {
+        fn string_array(vs: &Vec<String>) -> json::Json {
+            json::Json::Array(vs.iter().map(|v| {
+                json::Json::String(v.clone())
+            }).collect())
+        }
+        match *self {
+            Variable::Single(ref v) => json::Json::String(v.clone()),
+            Variable::Vector(ref vs) => string_array(vs),
+            Variable::MapSingle(ref m) => {
+                json::Json::Object(m.iter().map(|(k, v)| {
+                    (k.clone(), json::Json::String(v.clone()))
+                }).collect())
+            },
+            Variable::MapVector(ref m) => {
+                json::Json::Object(m.iter().map(|(k, vs)| {
+                    (k.clone(), string_array(vs))
+                }).collect())
+            },
+            Variable::Counter(n, ref c) => {
+                let mut m = BTreeMap::new();
+                m.insert("counter".to_string(), json::Json::U64(n));
+                if let Some(ref c) = *c {
+                    m.insert("chapter".to_string(),
+                             json::Json::String(c.clone()));
+                }
+                json::Json::Object(m)
+            },
+        }
+    }
+}
+
+// Maximum number of before/after samples recorded per rule when explaining.
+const MAX_SAMPLES: usize = 3;
+
+/// What a rule did to the content, recorded when explaining rules.
+pub struct RuleReport {
+    pub name: String,
+    // Number of matches (or, for native rules, 1 if the chunk changed) per
+    // chunk source, only listing sources with at least one match.
+    pub matches: Vec<(String, usize)>,
+    // Matched text and its replacement.
+    pub samples: Vec<(String, String)>,
 }
 
 pub struct RulesEngine {
-    variables: BTreeMap<String, VarVariant>
+    variables: BTreeMap<String, Variable>,
+    explain: bool,
+    reports: Vec<RuleReport>,
+    skip_code: bool
+}
+
+fn snippet(text: &str) -> String {
+    let limit = 60;
+    let mut res: String = text.chars().take(limit).collect();
+    if text.chars().count() > limit {
+        res.push_str("...");
+    }
+    res.replace("\n", "\\n")
 }
 
 impl RulesEngine {
     pub fn new() -> RulesEngine {
         RulesEngine {
-            variables: BTreeMap::new()
+            variables: BTreeMap::new(),
+            explain: false,
+            reports: Vec::new(),
+            skip_code: false
         }
     }
 
+    /// Makes the engine record a report of what each rule matched.
+    pub fn set_explain(&mut self, explain: bool) {
+        self.explain = explain;
+    }
+
+    /// Makes variable substitution leave code blocks and code spans alone.
+    pub fn set_skip_code(&mut self, skip_code: bool) {
+        self.skip_code = skip_code;
+    }
+
+    pub fn reports(&self) -> &Vec<RuleReport> {
+        &self.reports
+    }
+
+    /// Writes a value to a variable. Counters are written with the given
+    /// scope, `chapter` being the number of the chapter being processed.
+    pub fn store_value(&mut self, action: &StorageAction, variable: &str,
+                       key: String, value: String, scope: CounterScope,
+                       chapter: &str) -> Result<(), String> {
+        match *action {
+            StorageAction::Increment | StorageAction::StoreCounter => {
+                return self.update_counter(action, variable, scope, &value,
+                                           chapter).map(|_| ());
+            },
+            _ => ()
+        }
+        let is_map = match *action {
+            StorageAction::StoreMapSingle |
+            StorageAction::StoreMapVector => true,
+            _ => false
+        };
+        let valid_key = regex::Regex::new("^[_0-9a-zA-Z]+$").unwrap();
+        if is_map && !valid_key.is_match(&key) {
+            return Err("Key '".to_string() + &key + "' for variable '" +
+                       variable + "' is invalid. Please only use " +
+                       "underscores, digits, and ASCII letters.");
+        }
+        if !self.variables.contains_key(variable) {
+            let var = match *action {
+                StorageAction::StoreSingle => Variable::Single(String::new()),
+                StorageAction::StoreVector => Variable::Vector(Vec::new()),
+                StorageAction::StoreMapSingle => {
+                    Variable::MapSingle(BTreeMap::new())
+                },
+                StorageAction::StoreMapVector => {
+                    Variable::MapVector(BTreeMap::new())
+                },
+                StorageAction::Increment | StorageAction::StoreCounter => {
+                    return Err("Counter '".to_string() + variable + "' " +
+                               "cannot be created by this storage action.");
+                }
+            };
+            self.variables.insert(variable.to_string(), var);
+        }
+        match (action, self.variables.get_mut(variable).unwrap()) {
+            (&StorageAction::StoreSingle, &mut Variable::Single(ref mut v)) => {
+                *v = value;
+            },
+            (&StorageAction::StoreVector,
+             &mut Variable::Vector(ref mut vs)) => {
+                vs.push(value);
+            },
+            (&StorageAction::StoreMapSingle,
+             &mut Variable::MapSingle(ref mut m)) => {
+                m.insert(key, value);
+            },
+            (&StorageAction::StoreMapVector,
+             &mut Variable::MapVector(ref mut m)) => {
+                m.entry(key).or_insert(Vec::new()).push(value);
+            },
+            (_, var) => {
+                return Err("Variable '".to_string() + variable + "' is a " +
+                           var.type_name() + " variable and cannot be " +
+                           "written with a different storage action.");
+            }
+        }
+        Ok(())
+    }
+
+    /// Increments a counter, or sets it to the number in `value`, and returns
+    /// its new value as text. `chapter` is the number of the chapter being
+    /// processed, which restarts chapter counters when it changes.
+    pub fn update_counter(&mut self, action: &StorageAction, variable: &str,
+                          scope: CounterScope, value: &str, chapter: &str)
+    -> Result<String, String> {
+        if !self.variables.contains_key(variable) {
+            let counted = match scope {
+                CounterScope::Global => None,
+                CounterScope::Chapter => Some(chapter.to_string())
+            };
+            self.variables.insert(variable.to_string(),
+                                  Variable::Counter(0, counted));
+        }
+        match self.variables.get_mut(variable).unwrap() {
+            &mut Variable::Counter(ref mut n, ref mut counted) => {
+                match (scope, counted.as_mut()) {
+                    (CounterScope::Global, None) => (),
+                    (CounterScope::Chapter, Some(ref mut c)) => {
+                        if *c != chapter {
+                            c.clear();
+                            c.push_str(chapter);
+                            *n = 0;
+                        }
+                    },
+                    _ => {
+                        return Err("Counter '".to_string() + variable +
+                                   "' cannot be used with both global and " +
+                                   "chapter scope.");
+                    }
+                }
+                match *action {
+                    StorageAction::Increment => { *n += 1; },
+                    _ => {
+                        *n = match value.trim().parse() {
+                            Ok(x) => x,
+                            Err(_) => {
+                                return Err("Value '".to_string() + value +
+                                           "' for counter '" + variable +
+                                           "' is not a number.");
+                            }
+                        };
+                    }
+                }
+                Ok(counter_text(*n, counted))
+            },
+            var => {
+                Err("Variable '".to_string() + variable + "' is a " +
+                    var.type_name() + " variable and cannot be written " +
+                    "with a different storage action.")
+            }
+        }
+    }
+
+    // The scope of a counter, if the variable is one.
+    fn counter_scope_of(&self, variable: &str) -> Option<CounterScope> {
+        match self.variables.get(variable) {
+            Some(&Variable::Counter(_, None)) => Some(CounterScope::Global),
+            Some(&Variable::Counter(_, Some(_))) => Some(CounterScope::Chapter),
+            _ => None
+        }
+    }
+
+    fn globals(&self) -> BTreeMap<String, template::Value> {
+        self.variables.iter()
+            .map(|(name, var)| (name.clone(), var.to_value())).collect()
+    }
+
     pub fn apply_rule(&mut self, rule: &RuleSpec,
+                      scripts: Option<&scripting::ScriptHost>,
                       content: &mut structure::Content) -> Result<(), String> {
         // We have validated regexps before, so this must work.
         let re = regex::Regex::new(&rule.regex).unwrap();
+        let mut report = RuleReport {
+            name: rule.name.clone(),
+            matches: Vec::new(),
+            samples: Vec::new()
+        };
+        // We have validated contexts before, so this must work too.
+        let context = rule.rule_context().unwrap();
+        // Scripts see the variables in a table which is built once, then kept
+        // up to date with what each match stores.
+        let script = match (scripts, rule.script.as_ref()) {
+            (Some(host), Some(f)) => {
+                match host.set_variables(&self.globals()) {
+                    Ok(_) => Some((host, f)),
+                    Err(e) => {
+                        return Err(e + " (when applying rule '" +
+                                   &rule.name + "')");
+                    }
+                }
+            },
+            _ => None
+        };
         // Match and perform operations.
-        for mut chunk in content.chunks.iter_mut() {
-            // Process each match for storage
+        for ((chunk, source), chapter) in content.chunks.iter_mut()
+                                          .zip(content.sources.iter())
+                                          .zip(content.chapters.iter()) {
+            let zones = if context == RuleContext::Anywhere {
+                markdown::Zones { code: Vec::new(), html: Vec::new() }
+            } else {
+                markdown::zones(&chunk)
+            };
+            let mut new_chunk = String::with_capacity(chunk.len());
+            let mut last = 0;
+            let mut count = 0;
             for cap in re.captures_iter(&chunk) {
+                let (start, end) = cap.pos(0).unwrap();
+                if !context.accepts(&zones, start, end) {
+                    continue;
+                }
+                // A script sees the variables as they were before this match
+                // was stored.
+                let scripted = match script {
+                    Some((host, f)) => {
+                        let captures = (0..cap.len()).map(|i| {
+                            cap.at(i).unwrap_or("").to_string()
+                        }).collect();
+                        match host.call(f, captures, source) {
+                            Ok(x) => Some(x),
+                            Err(e) => {
+                                return Err(e + " (when applying rule '" +
+                                           &rule.name + "')");
+                            }
+                        }
+                    },
+                    _ => None
+                };
+                count += 1;
+                // Process the match for storage. `$counter` stands for the
+                // value of the last counter written for this match.
+                let mut counter = None;
                 for s in rule.storage.iter() {
-                    let processed_key = cap.expand(s.key.as_str());
-                    let valid_key = regex::Regex::new(
-                        "^[_0-9a-zA-Z]+$").unwrap();
-                    if !valid_key.is_match(&processed_key) {
-                        return Err("Captured key '".to_string() +
-                                   &processed_key +
-                                   "' obtained from text when applying rule '" +
-                                   &rule.name +
-                                   "' is invalid. Please only use " +
-                                   "underscores, digits, and ASCII letters.");
+                    let processed_key = cap.expand(&expand_counter(&s.key,
+                                                                   &counter));
+                    let processed_value = cap.expand(
+                        &expand_counter(&s.replace, &counter));
+                    let res = match s.action {
+                        StorageAction::Increment |
+                        StorageAction::StoreCounter => {
+                            // We have validated scopes before.
+                            let scope = s.counter_scope().unwrap();
+                            self.update_counter(&s.action, &s.variable, scope,
+                                                &processed_value, chapter)
+                                .map(|c| { counter = Some(c); })
+                        },
+                        _ => self.store_value(&s.action, &s.variable,
+                                              processed_key, processed_value,
+                                              CounterScope::Global, chapter)
+                    };
+                    match res {
+                        Ok(_) => (),
+                        Err(e) => {
+                            return Err(e + " (when applying rule '" +
+                                       &rule.name + "')");
+                        }
                     }
-                    let processed_value = cap.expand(s.replace.as_str());
-                    if !self.variables.contains_key(&s.variable) {
-                        self.variables.insert(s.variable.clone(), VarVariant {
-                            single: String::new(),
-                            vector: Vec::new(),
-                            map_single: BTreeMap::new(),
-                            map_vector: BTreeMap::new()
-                        });
+                }
+                if let Some(ref res) = scripted {
+                    for &(ref action, ref variable, ref key, ref value,
+                          scope) in res.storage.iter() {
+                        // Without a scope, counters keep the one they have.
+                        let scope = scope.or(self.counter_scope_of(variable))
+                                    .unwrap_or(CounterScope::Global);
+                        match self.store_value(action, variable, key.clone(),
+                                               value.clone(), scope,
+                                               chapter) {
+                            Ok(_) => (),
+                            Err(e) => {
+                                return Err(e + " (when applying rule '" +
+                                           &rule.name + "')");
+                            }
+                        }
                     }
-                    match s.action {
-                        StorageAction::StoreSingle => {
-                            self.variables.get_mut(&s.variable).unwrap(
-                                ).single = processed_value;
-                        },
-                        StorageAction::StoreVector => {
-                            self.variables.get_mut(&s.variable).unwrap(
-                                ).vector.push(processed_value);
-                        },
-                        StorageAction::StoreMapSingle => {
-                            self.variables.get_mut(&s.variable).unwrap(
-                                ).map_single.insert(processed_key,
-                                                    processed_value);
-                        },
-                        StorageAction::StoreMapVector => {
-                            let must_init = !self.variables.get(&s.variable)
-                                            .unwrap().map_vector
-                                            .contains_key(&processed_key);
-                            if must_init {
-                                self.variables.get_mut(&s.variable).unwrap()
-                                    .map_vector.insert(
-                                        processed_key.clone(), Vec::new());
+                }
+                if let Some((host, _)) = script {
+                    let stored = rule.storage.iter().map(|s| &s.variable)
+                                 .chain(scripted.iter().flat_map(|res| {
+                                     res.storage.iter().map(|u| &u.1)
+                                 }));
+                    for variable in stored {
+                        let value = self.variables[variable].to_value();
+                        match host.set_variable(variable, &value) {
+                            Ok(_) => (),
+                            Err(e) => {
+                                return Err(e + " (when applying rule '" +
+                                           &rule.name + "')");
                             }
-                            self.variables.get_mut(&s.variable).unwrap(
-                                ).map_vector.get_mut(&processed_key).unwrap(
-                                    ).push(processed_value);
-                        },
+                        }
                     }
                 }
+                let replacement = match scripted.as_ref()
+                                        .and_then(|s| s.replace.as_ref()) {
+                    Some(r) => r.clone(),
+                    None => cap.expand(&expand_counter(&rule.replace, &counter))
+                };
+                if self.explain && report.samples.len() < MAX_SAMPLES {
+                    report.samples.push((snippet(cap.at(0).unwrap()),
+                                         snippet(&replacement)));
+                }
+                // Then replace it
+                new_chunk.push_str(&chunk[last..start]);
+                new_chunk.push_str(&replacement);
+                last = end;
+            }
+            if count == 0 {
+                continue;
             }
-            // Finally replace all matches
-            let new_chunk = re.replace_all(&chunk, rule.replace.as_str());
-            // Surely there's an easier way of doing this...
+            if self.explain {
+                report.matches.push((source.clone(), count));
+            }
+            new_chunk.push_str(&chunk[last..]);
             // Why isn't there a String.swap()?
             chunk.clear();
             chunk.push_str(&new_chunk);
         }
+        if self.explain {
+            self.reports.push(report);
+        }
+        Ok(())
+    }
+
+    fn apply_native_rule(&mut self, rule: &builtins::NativeRule,
+                         content: &mut structure::Content)
+    -> Result<(), String> {
+        if !self.explain {
+            return rule.apply(self, content);
+        }
+        let before = content.chunks.clone();
+        match rule.apply(self, content) {
+            Ok(_) => (),
+            Err(e) => { return Err(e); }
+        }
+        let matches = before.iter().zip(content.chunks.iter())
+                      .zip(content.sources.iter())
+                      .filter(|&((b, a), _)| b != a)
+                      .map(|(_, source)| (source.clone(), 1))
+                      .collect();
+        self.reports.push(RuleReport {
+            name: rule.name().to_string(),
+            matches: matches,
+            samples: Vec::new()
+        });
         Ok(())
     }
 
     pub fn apply_rules(&mut self, rules: &RuleSpecContainer,
                        content: &mut structure::Content) -> Result<(), String> {
         for rule in rules.rules.iter() {
-            match self.apply_rule(rule, content) {
+            let res = match *rule {
+                Rule::Spec(ref r) => {
+                    self.apply_rule(r, rules.scripts.as_ref(), content)
+                },
+                Rule::Native(ref n) => self.apply_native_rule(n, content)
+            };
+            match res {
                 Ok(_) => (),
                 Err(e) => { return Err(e); }
             }
@@ -186,130 +778,335 @@ impl RulesEngine {
         Ok(())
     }
 
-    fn construct_map_content(&self, m: &BTreeMap<String, String>) -> String {
-        m.iter().map(|(k, v)| {
-            format!("{}: {}", k, v)
-        }).fold(String::new(), |acc, x| {
-            acc + "\n\n" + &x
-        })
-    }
-
-    fn construct_mapv_content(&self, m: &BTreeMap<String, Vec<String>>) -> String {
-        m.iter().map(|(k, v)| {
-            format!("{}: {}", k, v.iter().fold(String::new(), |acc, x| {
-                acc + " " + &x
-            }))
-        }).fold(String::new(), |acc, x| {
-            acc + "\n\n" + &x
-        })
+    /// Serialises all variables to JSON. Single variables become strings,
+    /// vector variables arrays, map-of-single variables objects of strings
+    /// and map-of-vector variables objects of arrays. Counters become objects
+    /// with a numeric "counter" field and, for chapter counters, a "chapter"
+    /// field.
+    pub fn variables_to_json(&self) -> String {
+        let root: BTreeMap<String, json::Json> = self.variables.iter()
+            .map(|(name, var)| (name.clone(), var.to_json())).collect();
+        format!("{}\n", json::Json::Object(root).pretty())
     }
 
-    pub fn substitute_vars(&self, content: &mut structure::Content) 
-    -> Result<(), String>  {
-        let re_keyed_var = regex::Regex::new(
-            r"\{\{\s*([_0-9a-zA-Z]+)\.([_0-9a-zA-Z]+)\s*\}\}").unwrap();
-        let re_plain_var = regex::Regex::new(
-            r"\{\{\s*([_0-9a-zA-Z]+)\s*\}\}").unwrap();
-        for mut chunk in content.chunks.iter_mut() {
-            // Loop until there isn't anything to substitute anymore.
-            while let Some(m) = re_keyed_var.captures(&chunk.clone()) {
-                let var_name = m.at(1).unwrap();
-                let var_key = m.at(2).unwrap();
-                match self.variables.get(var_name) {
-                    Some(var) => {
-                        match var.map_single.get(var_key) {
-                            Some(value) => {
-                                let new_chunk = re_keyed_var.replace(
-                                    &chunk, regex::NoExpand(value));
-                                // Why isn't there a String.swap()?
-                                chunk.clear();
-                                chunk.push_str(&new_chunk);
-                                continue;
-                            },
-                            None => ()
-                        }
-                        match var.map_vector.get(var_key) {
-                            Some(vector) => {
-                                let new_chunk = re_keyed_var.replace(
-                                    &chunk, regex::NoExpand(
-                                        &vector.join("\n\n")));
-                                // Why isn't there a String.swap()?
-                                chunk.clear();
-                                chunk.push_str(&new_chunk);
-                                continue;
-                            },
-                            None => ()
-                        }
-                        return Err("Variable '".to_string() +
-                                   &var_name +
-                                   "' does not contain key '" +
-                                   &var_key + "' at the point of " +
-                                   "variable substitution.");
+    fn variable_from_json(name: &str, value: &json::Json)
+    -> Result<Variable, String> {
+        let invalid = "Variable '".to_string() + name + "' must be a " +
+                      "string, an array of strings, an object whose values " +
+                      "are all strings, an object whose values are all " +
+                      "arrays of strings, or a counter object.";
+        fn strings(vs: &Vec<json::Json>) -> Option<Vec<String>> {
+            vs.iter().map(|v| v.as_string().map(|x| x.to_string())).collect()
+        }
+        match *value {
+            json::Json::String(ref v) => Ok(Variable::Single(v.clone())),
+            json::Json::Array(ref vs) => {
+                match strings(vs) {
+                    Some(x) => Ok(Variable::Vector(x)),
+                    None => Err(invalid)
+                }
+            },
+            // A number is never a valid value for the other kinds of object.
+            json::Json::Object(ref m) if m.get("counter")
+                                          .map_or(false, |c| c.is_number()) => {
+                let n = m.get("counter").and_then(|c| c.as_u64());
+                let chapter = m.get("chapter").map(|c| {
+                    c.as_string().map(|x| x.to_string())
+                });
+                match (n, chapter, m.len()) {
+                    (Some(n), None, 1) => Ok(Variable::Counter(n, None)),
+                    (Some(n), Some(Some(c)), 2) => {
+                        Ok(Variable::Counter(n, Some(c)))
                     },
-                    None => {
-                        return Err("Variable '".to_string() +
-                                   &var_name +
-                                   "' is not defined at the point of " +
-                                   "variable substitution.");
+                    _ => {
+                        Err("Counter '".to_string() + name + "' must be an " +
+                            "object with a non-negative integer \"counter\" " +
+                            "field and an optional \"chapter\" string.")
                     }
                 }
+            },
+            json::Json::Object(ref m) => {
+                let valid_key = regex::Regex::new("^[_0-9a-zA-Z]+$").unwrap();
+                for k in m.keys() {
+                    if !valid_key.is_match(k) {
+                        return Err("Key '".to_string() + k + "' of " +
+                                   "variable '" + name + "' is invalid. " +
+                                   "Please only use underscores, digits, " +
+                                   "and ASCII letters.");
+                    }
+                }
+                // An empty object is taken to be a map-of-single.
+                if m.values().all(|v| v.is_string()) {
+                    return Ok(Variable::MapSingle(m.iter().map(|(k, v)| {
+                        (k.clone(), v.as_string().unwrap().to_string())
+                    }).collect()));
+                }
+                let mut res = BTreeMap::new();
+                for (k, v) in m.iter() {
+                    match v.as_array().and_then(strings) {
+                        Some(x) => { res.insert(k.clone(), x); },
+                        None => { return Err(invalid); }
+                    }
+                }
+                Ok(Variable::MapVector(res))
+            },
+            _ => Err(invalid)
+        }
+    }
+
+    // Preloaded variables replace any previous definition, whatever its type.
+    fn load_variable(&mut self, name: &str, value: &json::Json)
+    -> Result<(), String> {
+        let valid_names = regex::Regex::new("^[_0-9a-zA-Z]+$").unwrap();
+        if !valid_names.is_match(name) {
+            return Err("Variable name '".to_string() + name + "' is " +
+                       "invalid. Please only use underscores, digits and " +
+                       "ASCII letters.");
+        }
+        match RulesEngine::variable_from_json(name, value) {
+            Ok(var) => {
+                self.variables.insert(name.to_string(), var);
+                Ok(())
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Preloads variables from a JSON object in the format produced by
+    /// `variables_to_json`.
+    pub fn load_variables_json(&mut self, js: &str) -> Result<(), String> {
+        let root = match json::Json::from_str(js) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Error parsing variables JSON: {}", e));
             }
-            while let Some(m) = re_plain_var.captures(&chunk.clone()) {
-                let var_name = m.at(1).unwrap();
-                match self.variables.get(var_name) {
-                    Some(var) => {
-                        if !var.single.is_empty() {
-                            let new_chunk = re_plain_var.replace(
-                                &chunk, regex::NoExpand(&var.single));
-                            // Why isn't there a String.swap()?
-                            chunk.clear();
-                            chunk.push_str(&new_chunk);
-                            continue;
-                        }
-                        if !var.vector.is_empty() {
-                            let new_chunk = re_plain_var.replace(
-                                &chunk, regex::NoExpand(
-                                    &var.vector.join("\n\n")));
-                            // Why isn't there a String.swap()?
-                            chunk.clear();
-                            chunk.push_str(&new_chunk);
-                            continue;
-                        }
-                        if !var.map_single.is_empty() {
-                            let new_chunk = re_plain_var.replace(
-                                &chunk, regex::NoExpand(
-                                    &self.construct_map_content(
-                                        &var.map_single)));
-                            // Why isn't there a String.swap()?
-                            chunk.clear();
-                            chunk.push_str(&new_chunk);
-                            continue;
-                        }
-                        if !var.map_vector.is_empty() {
-                            let new_chunk = re_plain_var.replace(
-                                &chunk, regex::NoExpand(
-                                    &self.construct_mapv_content(
-                                        &var.map_vector)));
-                            // Why isn't there a String.swap()?
-                            chunk.clear();
-                            chunk.push_str(&new_chunk);
-                            continue;
+        };
+        let vars = match root.as_object() {
+            Some(x) => x,
+            None => {
+                return Err("Error parsing variables JSON: the top level \
+                            value must be an object.".to_string());
+            }
+        };
+        for (name, value) in vars.iter() {
+            match self.load_variable(name, value) {
+                Ok(_) => (),
+                Err(e) => { return Err(e); }
+            }
+        }
+        Ok(())
+    }
+
+    /// Preloads a single variable from a `name=value` definition.
+    pub fn load_variable_definition(&mut self, def: &str)
+    -> Result<(), String> {
+        match def.find('=') {
+            Some(i) => {
+                let value = json::Json::String(def[i + 1..].to_string());
+                self.load_variable(&def[..i], &value)
+            },
+            None => {
+                Err("Variable definition '".to_string() + def + "' must be " +
+                    "of the form name=value.")
+            }
+        }
+    }
+
+    /// Describes the contents of every storage variable, for diagnostics.
+    pub fn describe_variables(&self) -> String {
+        let mut res = String::new();
+        for (name, var) in self.variables.iter() {
+            res = res + name + " (" + var.type_name() + "):\n";
+            match *var {
+                Variable::Single(ref v) => {
+                    res = res + "    \"" + &snippet(v) + "\"\n";
+                },
+                Variable::Vector(ref vs) => {
+                    for v in vs.iter() {
+                        res = res + "    - \"" + &snippet(v) + "\"\n";
+                    }
+                },
+                Variable::MapSingle(ref m) => {
+                    for (k, v) in m.iter() {
+                        res = res + "    " + k + ": \"" + &snippet(v) + "\"\n";
+                    }
+                },
+                Variable::MapVector(ref m) => {
+                    for (k, vs) in m.iter() {
+                        res = res + "    " + k + ":\n";
+                        for v in vs.iter() {
+                            res = res + "        - \"" + &snippet(v) + "\"\n";
                         }
-                        return Err("Variable '".to_string() +
-                                   &var_name +
-                                   "' does not contain content " +
-                                   "at the point of " +
-                                   "variable substitution.");
-                    },
-                    None => {
-                        return Err("Variable '".to_string() +
-                                   &var_name +
-                                   "' is not defined at the point of " +
-                                   "variable substitution.");
                     }
-                }
+                },
+                Variable::Counter(n, ref c) => {
+                    res = res + "    " + &counter_text(n, c) + "\n";
+                },
             }
         }
+        res
+    }
+
+    /// Substitutes variables in all chunks, rendering each chunk as a
+    /// template. See the `template` module for the syntax.
+    pub fn substitute_vars(&self, content: &mut structure::Content) 
+    -> Result<(), String>  {
+        let globals = self.globals();
+        for (chunk, source) in content.chunks.iter_mut()
+                               .zip(content.sources.iter()) {
+            let new_chunk = match template::render(&chunk, &globals,
+                                                   self.skip_code) {
+                Ok(x) => x,
+                Err(e) => { return Err(e + " (in " + source + ")"); }
+            };
+            // Why isn't there a String.swap()?
+            chunk.clear();
+            chunk.push_str(&new_chunk);
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json;
+
+    use structure::Content;
+
+    use super::{counter_text, expand_counter, CounterScope, RulesEngine,
+                StorageAction};
+
+    fn store(engine: &mut RulesEngine, action: StorageAction, variable: &str,
+             key: &str, value: &str) -> Result<(), String> {
+        engine.store_value(&action, variable, key.to_string(),
+                           value.to_string(), CounterScope::Global, "")
+    }
+
+    fn vars(engine: &RulesEngine) -> json::Json {
+        json::Json::from_str(&engine.variables_to_json()).unwrap()
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let js = r#"{"m":{"k":"a"},"mv":{"k":["a","b"]},"s":"x","v":["a"]}"#;
+        let mut e = RulesEngine::new();
+        e.load_variables_json(js).unwrap();
+        assert_eq!(vars(&e).to_string(), js);
+        for bad in [r#"{"v":[1]}"#, r#"{"m":{"k":1}}"#,
+                    r#"{"m":{"bad key":"x"}}"#, r#"{"bad name":"x"}"#,
+                    r#"[]"#, "{"].iter() {
+            assert!(RulesEngine::new().load_variables_json(bad).is_err(),
+                    "{}", bad);
+        }
+    }
+
+    #[test]
+    fn variable_definitions() {
+        let mut e = RulesEngine::new();
+        e.load_variable_definition("edition=2=b").unwrap();
+        assert_eq!(vars(&e).to_string(), r#"{"edition":"2=b"}"#);
+        assert!(e.load_variable_definition("edition").is_err());
+        assert!(e.load_variable_definition("bad name=x").is_err());
+    }
+
+    #[test]
+    fn variable_types() {
+        let mut e = RulesEngine::new();
+        store(&mut e, StorageAction::StoreSingle, "s", "", "a").unwrap();
+        store(&mut e, StorageAction::StoreSingle, "s", "", "b").unwrap();
+        store(&mut e, StorageAction::StoreVector, "v", "", "a").unwrap();
+        store(&mut e, StorageAction::StoreVector, "v", "", "b").unwrap();
+        store(&mut e, StorageAction::StoreMapSingle, "m", "k", "a").unwrap();
+        store(&mut e, StorageAction::StoreMapSingle, "m", "k", "b").unwrap();
+        store(&mut e, StorageAction::StoreMapVector, "mv", "k", "a").unwrap();
+        store(&mut e, StorageAction::StoreMapVector, "mv", "k", "b").unwrap();
+        assert_eq!(vars(&e).to_string(),
+                   concat!(r#"{"m":{"k":"b"},"mv":{"k":["a","b"]},"#,
+                           r#""s":"b","v":["a","b"]}"#));
+        // The first write decides the type.
+        assert!(store(&mut e, StorageAction::StoreVector, "s", "", "c")
+                .is_err());
+        assert!(store(&mut e, StorageAction::StoreMapSingle, "m", "bad key",
+                      "c").is_err());
+    }
+
+    #[test]
+    fn global_counters() {
+        let mut e = RulesEngine::new();
+        let next = |e: &mut RulesEngine, chapter: &str| {
+            e.update_counter(&StorageAction::Increment, "c",
+                             CounterScope::Global, "", chapter).unwrap()
+        };
+        assert_eq!(next(&mut e, "1.1"), "1");
+        assert_eq!(next(&mut e, "1.2"), "2");
+        assert_eq!(e.update_counter(&StorageAction::StoreCounter, "c",
+                                    CounterScope::Global, " 10 ", "1.2"),
+                   Ok("10".to_string()));
+        assert_eq!(next(&mut e, "1.2"), "11");
+        assert!(e.update_counter(&StorageAction::StoreCounter, "c",
+                                 CounterScope::Global, "ten", "1.2").is_err());
+        assert!(e.update_counter(&StorageAction::Increment, "c",
+                                 CounterScope::Chapter, "", "1.2").is_err());
+        assert!(store(&mut e, StorageAction::StoreSingle, "c", "", "x")
+                .is_err());
+    }
+
+    #[test]
+    fn chapter_counters() {
+        let mut e = RulesEngine::new();
+        let next = |e: &mut RulesEngine, chapter: &str| {
+            e.update_counter(&StorageAction::Increment, "fig",
+                             CounterScope::Chapter, "", chapter).unwrap()
+        };
+        assert_eq!(next(&mut e, "1.1"), "1.1.1");
+        assert_eq!(next(&mut e, "1.1"), "1.1.2");
+        assert_eq!(next(&mut e, "2.1"), "2.1.1");
+        assert_eq!(vars(&e).to_string(),
+                   r#"{"fig":{"chapter":"2.1","counter":1}}"#);
+        assert_eq!(counter_text(4, &None), "4");
+        assert_eq!(counter_text(4, &Some("3".to_string())), "3.4");
+        assert_eq!(counter_text(4, &Some(String::new())), "4");
+    }
+
+    #[test]
+    fn counter_round_trip() {
+        let js = concat!(r#"{"c":{"counter":3},"#,
+                         r#""f":{"chapter":"1.2","counter":2}}"#);
+        let mut e = RulesEngine::new();
+        e.load_variables_json(js).unwrap();
+        assert_eq!(vars(&e).to_string(), js);
+        assert_eq!(e.update_counter(&StorageAction::Increment, "f",
+                                    CounterScope::Chapter, "", "1.2"),
+                   Ok("1.2.3".to_string()));
+        for bad in [r#"{"c":{"counter":-1}}"#, r#"{"c":{"counter":1,"x":2}}"#,
+                    r#"{"c":{"counter":1,"chapter":2}}"#].iter() {
+            assert!(RulesEngine::new().load_variables_json(bad).is_err(),
+                    "{}", bad);
+        }
+    }
+
+    #[test]
+    fn counter_expansion() {
+        let c = Some("4".to_string());
+        assert_eq!(expand_counter("$counter ${counter}x $counters $$counter $1",
+                                  &c),
+                   "4 4x $counters $$counter $1");
+        assert_eq!(expand_counter("$counter", &None), "$counter");
+    }
+
+    #[test]
+    fn counters_in_rules() {
+        let rsc = super::RuleSpecContainer::from_json(r#"{"rules": [{
+            "name": "figures", "regex": "FIG", "replace": "Figure $counter",
+            "storage": [{"variable": "fig", "action": "Increment",
+                         "key": "", "replace": "", "scope": "chapter"}]
+        }]}"#).unwrap();
+        let mut content = Content::new();
+        content.push_chunk("a.md", "1.1", "FIG FIG".to_string());
+        content.push_chunk("b.md", "1.2", "FIG".to_string());
+        let mut e = RulesEngine::new();
+        e.apply_rules(&rsc, &mut content).unwrap();
+        assert_eq!(content.chunks, vec!["Figure 1.1.1 Figure 1.1.2",
+                                        "Figure 1.2.1"]);
+    }
+}
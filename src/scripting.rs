@@ -0,0 +1,483 @@
+// Copyright 2016 Dario Domizioli
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use mlua;
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib};
+use regex;
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::fs::File;
+use std::rc::Rc;
+
+use rules::{CounterScope, StorageAction};
+use template;
+
+/// What a script function asked for when called on a match.
+pub struct ScriptResult {
+    // The replacement text, or None to use the "replace" field of the rule.
+    pub replace: Option<String>,
+    // Storage updates as (action, variable, key, value, counter scope).
+    pub storage: Vec<(StorageAction, String, String, String,
+                      Option<CounterScope>)>,
+}
+
+// How often the instruction count is checked, and how many instructions a
+// script file or function may run before it is stopped.
+const HOOK_INTERVAL: u32 = 1000;
+const MAX_INSTRUCTIONS: u32 = 100_000_000;
+
+// How much memory the Lua state may allocate, in bytes.
+const MAX_MEMORY: usize = 256 * 1024 * 1024;
+
+// The name of the registry value holding the table of storage variables.
+const VARIABLES: &'static str = "kos_variables";
+
+/// The Lua state holding the functions defined by the script files of a
+/// rules file.
+pub struct ScriptHost {
+    lua: Lua,
+    files: Vec<String>,
+    // The number of instructions run so far by the current script file or
+    // function.
+    instructions: Rc<Cell<u32>>
+}
+
+fn to_lua<'lua>(lua: &'lua Lua, value: &template::Value)
+-> mlua::Result<mlua::Value<'lua>> {
+    match *value {
+        template::Value::Str(ref s) => {
+            lua.create_string(s).map(mlua::Value::String)
+        },
+        template::Value::List(ref vs) => {
+            let t = match lua.create_table() {
+                Ok(x) => x,
+                Err(e) => { return Err(e); }
+            };
+            for (i, v) in vs.iter().enumerate() {
+                match to_lua(lua, v).and_then(|x| t.set(i + 1, x)) {
+                    Ok(_) => (),
+                    Err(e) => { return Err(e); }
+                }
+            }
+            Ok(mlua::Value::Table(t))
+        },
+        template::Value::Map(ref m) => {
+            let t = match lua.create_table() {
+                Ok(x) => x,
+                Err(e) => { return Err(e); }
+            };
+            for (k, v) in m.iter() {
+                match to_lua(lua, v).and_then(|x| t.set(k.as_str(), x)) {
+                    Ok(_) => (),
+                    Err(e) => { return Err(e); }
+                }
+            }
+            Ok(mlua::Value::Table(t))
+        },
+    }
+}
+
+// Describes a Lua error, spelling out the memory limit when it was hit.
+fn describe(e: mlua::Error) -> String {
+    match e {
+        mlua::Error::MemoryError(_) => {
+            format!("ran out of memory (the limit is {} MB)",
+                    MAX_MEMORY / (1024 * 1024))
+        },
+        e => e.to_string()
+    }
+}
+
+fn storage_action(name: &str) -> Option<StorageAction> {
+    match name {
+        "StoreSingle" => Some(StorageAction::StoreSingle),
+        "StoreVector" => Some(StorageAction::StoreVector),
+        "StoreMapSingle" => Some(StorageAction::StoreMapSingle),
+        "StoreMapVector" => Some(StorageAction::StoreMapVector),
+        "Increment" => Some(StorageAction::Increment),
+        "StoreCounter" => Some(StorageAction::StoreCounter),
+        _ => None
+    }
+}
+
+fn storage_update(update: &mlua::Table, function: &str)
+-> Result<(StorageAction, String, String, String, Option<CounterScope>),
+          String> {
+    let fields = (update.get::<_, Option<String>>("action"),
+                  update.get::<_, Option<String>>("variable"),
+                  update.get::<_, Option<String>>("key"),
+                  update.get::<_, Option<String>>("value"),
+                  update.get::<_, Option<String>>("scope"));
+    match fields {
+        (Ok(Some(action)), Ok(Some(variable)), Ok(key), Ok(Some(value)),
+         Ok(scope)) => {
+            let valid_names = regex::Regex::new("^[_0-9a-zA-Z]+$").unwrap();
+            if !valid_names.is_match(&variable) {
+                return Err("Variable name '".to_string() + &variable +
+                           "' returned by script function '" + function +
+                           "' is invalid. Please only use underscores, " +
+                           "digits and ASCII letters.");
+            }
+            let scope = match scope.as_ref().map(|s| s.as_str()) {
+                None => None,
+                Some("global") => Some(CounterScope::Global),
+                Some("chapter") => Some(CounterScope::Chapter),
+                Some(s) => {
+                    return Err("Scope '".to_string() + s + "' returned by " +
+                               "script function '" + function + "' is " +
+                               "invalid. Please use 'global' or 'chapter'.");
+                }
+            };
+            match storage_action(&action) {
+                Some(a) => Ok((a, variable, key.unwrap_or(String::new()),
+                               value, scope)),
+                None => {
+                    Err("Storage action '".to_string() + &action +
+                        "' returned by script function '" + function +
+                        "' is invalid.")
+                }
+            }
+        },
+        _ => {
+            Err("Storage updates returned by script function '".to_string() +
+                function + "' must be tables with an \"action\", a " +
+                "\"variable\", a \"value\" and an optional \"key\" and " +
+                "\"scope\", all strings.")
+        }
+    }
+}
+
+impl ScriptHost {
+    /// Reads and runs the given script files, in order, in a fresh Lua state
+    /// which only has the table, string, math and utf8 standard libraries.
+    pub fn load(files: &Vec<String>) -> Result<ScriptHost, String> {
+        let libs = StdLib::TABLE | StdLib::STRING | StdLib::MATH |
+                   StdLib::UTF8;
+        let lua = match Lua::new_with(libs, LuaOptions::new()) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Error creating Lua state: {}", e));
+            }
+        };
+        // The base library can still read files.
+        for name in ["dofile", "loadfile"].iter() {
+            match lua.globals().set(*name, mlua::Value::Nil) {
+                Ok(_) => (),
+                Err(e) => {
+                    return Err(format!("Error creating Lua state: {}", e));
+                }
+            }
+        }
+        // Stops scripts which allocate without bounds, such as
+        // string.rep("x", 1e9).
+        match lua.set_memory_limit(MAX_MEMORY) {
+            Ok(_) => (),
+            Err(e) => {
+                return Err(format!("Error creating Lua state: {}", e));
+            }
+        }
+        // Stops scripts which never end, such as an endless loop.
+        let instructions = Rc::new(Cell::new(0));
+        let count = instructions.clone();
+        let triggers = HookTriggers::new().every_nth_instruction(HOOK_INTERVAL);
+        lua.set_hook(triggers, move |_, _| {
+            count.set(count.get() + HOOK_INTERVAL);
+            if count.get() >= MAX_INSTRUCTIONS {
+                Err(mlua::Error::RuntimeError(format!(
+                    "stopped after running {} instructions", MAX_INSTRUCTIONS)))
+            } else {
+                Ok(())
+            }
+        });
+        for file in files.iter() {
+            let mut source = String::new();
+            match File::open(file) {
+                Ok(mut fread) => {
+                    match fread.read_to_string(&mut source) {
+                        Ok(_) => (),
+                        Err(_) => {
+                            return Err("Error reading script file '"
+                                       .to_string() + file + "'.");
+                        }
+                    }
+                },
+                Err(_) => {
+                    return Err("Error opening script file '".to_string() +
+                               file + "'.");
+                }
+            }
+            instructions.set(0);
+            match lua.load(&source).set_name(file.as_str()).exec() {
+                Ok(_) => (),
+                Err(e) => {
+                    return Err(format!("Error loading script file '{}': {}",
+                                       file, describe(e)));
+                }
+            }
+        }
+        Ok(ScriptHost {
+            lua: lua,
+            files: files.clone(),
+            instructions: instructions
+        })
+    }
+
+    /// The script files, in the order they were run.
+    pub fn files(&self) -> &Vec<String> {
+        &self.files
+    }
+
+    /// Whether a global function of the given name has been defined.
+    pub fn has_function(&self, name: &str) -> bool {
+        match self.lua.globals().get::<_, mlua::Value>(name) {
+            Ok(mlua::Value::Function(_)) => true,
+            _ => false
+        }
+    }
+
+    /// Sets the contents of all storage variables, as passed to the script
+    /// functions called afterwards.
+    pub fn set_variables(&self, variables: &BTreeMap<String, template::Value>)
+    -> Result<(), String> {
+        let res = self.lua.create_table().and_then(|vars| {
+            for (name, value) in variables.iter() {
+                match to_lua(&self.lua, value).and_then(|v| {
+                    vars.set(name.as_str(), v)
+                }) {
+                    Ok(_) => (),
+                    Err(e) => { return Err(e); }
+                }
+            }
+            self.lua.set_named_registry_value(VARIABLES, vars)
+        });
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Error passing variables to scripts: {}", e))
+        }
+    }
+
+    /// Updates the contents of one storage variable, as passed to the script
+    /// functions called afterwards.
+    pub fn set_variable(&self, name: &str, value: &template::Value)
+    -> Result<(), String> {
+        let res = self.lua.named_registry_value::<mlua::Table>(VARIABLES)
+                  .and_then(|vars| {
+                      to_lua(&self.lua, value).and_then(|v| vars.set(name, v))
+                  });
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Error passing variables to scripts: {}", e))
+        }
+    }
+
+    /// Calls a script function on a match. `captures` holds the whole match
+    /// followed by each group (empty if the group did not participate). The
+    /// function also gets the storage variables last set with
+    /// `set_variables` and `set_variable`.
+    pub fn call(&self, function: &str, captures: Vec<String>, source: &str)
+    -> Result<ScriptResult, String> {
+        let error = |e: mlua::Error| {
+            format!("Error in script function '{}': {}", function,
+                    describe(e))
+        };
+        let f = match self.lua.globals().get::<_, mlua::Function>(function) {
+            Ok(x) => x,
+            Err(e) => { return Err(error(e)); }
+        };
+        // Indexed from 0 like the groups in a "replace" string, so that
+        // caps[1] is $1.
+        let caps = match self.lua.create_table() {
+            Ok(x) => x,
+            Err(e) => { return Err(error(e)); }
+        };
+        for (i, c) in captures.into_iter().enumerate() {
+            match caps.set(i, c) {
+                Ok(_) => (),
+                Err(e) => { return Err(error(e)); }
+            }
+        }
+        let vars = match self.lua.named_registry_value::<mlua::Table>(
+                VARIABLES) {
+            Ok(x) => x,
+            Err(e) => { return Err(error(e)); }
+        };
+        self.instructions.set(0);
+        let res = match f.call::<_, mlua::Value>((caps, vars, source)) {
+            Ok(x) => x,
+            Err(e) => { return Err(error(e)); }
+        };
+        let table = match res {
+            mlua::Value::Nil => {
+                return Ok(ScriptResult { replace: None, storage: Vec::new() });
+            },
+            mlua::Value::String(s) => {
+                return match s.to_str() {
+                    Ok(x) => Ok(ScriptResult {
+                        replace: Some(x.to_string()),
+                        storage: Vec::new()
+                    }),
+                    Err(e) => Err(error(e))
+                };
+            },
+            mlua::Value::Table(t) => t,
+            _ => {
+                return Err("Script function '".to_string() + function +
+                           "' must return a string, a table or nothing.");
+            }
+        };
+        let replace = match table.get::<_, Option<String>>("replace") {
+            Ok(x) => x,
+            Err(_) => {
+                return Err("Field \"replace\" returned by script function '"
+                           .to_string() + function + "' must be a string.");
+            }
+        };
+        let updates = match table.get::<_, Option<mlua::Table>>("store") {
+            Ok(x) => x,
+            Err(_) => {
+                return Err("Field \"store\" returned by script function '"
+                           .to_string() + function + "' must be a table.");
+            }
+        };
+        let mut storage = Vec::new();
+        if let Some(updates) = updates {
+            for u in updates.sequence_values::<mlua::Table>() {
+                let u = match u {
+                    Ok(x) => x,
+                    Err(_) => {
+                        return Err("Field \"store\" returned by script \
+                                    function '".to_string() + function +
+                                   "' must be a list of tables.");
+                    }
+                };
+                match storage_update(&u, function) {
+                    Ok(x) => { storage.push(x); },
+                    Err(e) => { return Err(e); }
+                }
+            }
+        }
+        Ok(ScriptResult {
+            replace: replace,
+            storage: storage
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs::File;
+    use std::io::prelude::*;
+
+    use rules::{CounterScope, StorageAction};
+    use template::Value;
+
+    use super::ScriptHost;
+
+    fn host(name: &str, source: &str) -> Result<ScriptHost, String> {
+        let path = env::temp_dir().join(format!("kosbook_{}.lua", name));
+        File::create(&path).and_then(|mut f| f.write_all(source.as_bytes()))
+                           .unwrap();
+        ScriptHost::load(&vec![path.to_string_lossy().into_owned()])
+    }
+
+    fn call(host: &ScriptHost, function: &str) -> Result<String, String> {
+        host.set_variables(&BTreeMap::new()).unwrap();
+        host.call(function, vec!["x".to_string()], "a.md")
+            .map(|r| r.replace.unwrap_or("(none)".to_string()))
+    }
+
+    #[test]
+    fn sandbox() {
+        let h = host("sandbox", "function libs()
+            return tostring(io) .. tostring(os) .. tostring(require) ..
+                   tostring(dofile) .. tostring(loadfile) .. tostring(debug)
+        end").unwrap();
+        assert_eq!(call(&h, "libs"), Ok("nilnilnilnilnilnil".to_string()));
+        assert!(host("sandbox_io", "io.open('x')").is_err());
+        assert!(h.has_function("libs"));
+        assert!(!h.has_function("nothing"));
+    }
+
+    #[test]
+    fn limits() {
+        let h = host("limits", "function spin() while true do end end
+            function big() return string.rep('x', 1e9) end
+            function fine() return 'fine' end").unwrap();
+        let e = call(&h, "spin").unwrap_err();
+        assert!(e.contains("'spin'") && e.contains("instructions"), "{}", e);
+        let e = call(&h, "big").unwrap_err();
+        assert!(e.contains("'big'") && e.contains("out of memory"), "{}", e);
+        // The limits are per call, and the state is still usable.
+        assert_eq!(call(&h, "fine"), Ok("fine".to_string()));
+        assert!(host("limits_load", "while true do end").is_err());
+    }
+
+    #[test]
+    fn results() {
+        let h = host("results", "
+            function upper(caps, vars, source)
+                return string.upper(caps[0]) .. vars.edition .. source
+            end
+            function none() end
+            function full(caps)
+                return { replace = 'R', store = {
+                    { action = 'StoreMapSingle', variable = 'm', key = 'k',
+                      value = caps[0] },
+                    { action = 'Increment', variable = 'c', value = '',
+                      scope = 'chapter' } } }
+            end
+            function keep() return { store = {} } end
+            function number() return 1 end
+            function bad_action()
+                return { store = { { action = 'Store', variable = 'v',
+                                     value = '' } } }
+            end
+            function bad_scope()
+                return { store = { { action = 'Increment', variable = 'v',
+                                     value = '', scope = 'book' } } }
+            end
+            function bad_name()
+                return { store = { { action = 'StoreSingle',
+                                     variable = 'a b', value = '' } } }
+            end
+            function bad_store() return { store = 'x' } end
+        ").unwrap();
+        let mut vars = BTreeMap::new();
+        vars.insert("edition".to_string(), Value::Str("1".to_string()));
+        h.set_variables(&vars).unwrap();
+        let r = h.call("upper", vec!["x".to_string()], "a.md").unwrap();
+        assert_eq!(r.replace, Some("X1a.md".to_string()));
+        h.set_variable("edition", &Value::Str("2".to_string())).unwrap();
+        let r = h.call("upper", vec!["x".to_string()], "a.md").unwrap();
+        assert_eq!(r.replace, Some("X2a.md".to_string()));
+        assert_eq!(call(&h, "none"), Ok("(none)".to_string()));
+        assert_eq!(call(&h, "keep"), Ok("(none)".to_string()));
+        let r = h.call("full", vec!["x".to_string()], "a.md").unwrap();
+        assert_eq!(r.replace, Some("R".to_string()));
+        assert!(r.storage == vec![
+            (StorageAction::StoreMapSingle, "m".to_string(),
+             "k".to_string(), "x".to_string(), None),
+            (StorageAction::Increment, "c".to_string(), String::new(),
+             String::new(), Some(CounterScope::Chapter))]);
+        for f in ["number", "bad_action", "bad_scope", "bad_name",
+                  "bad_store"].iter() {
+            let e = call(&h, f).unwrap_err();
+            assert!(e.contains(&format!("'{}'", f)), "{}", e);
+        }
+    }
+}
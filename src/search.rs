@@ -0,0 +1,206 @@
+// Copyright 2016 Dario Domizioli
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use pulldown_cmark::{Event, Tag};
+use rustc_serialize::json;
+
+use std::collections::{BTreeMap, BTreeSet};
+use regex;
+
+/// The script searching the index as the reader types into the element with
+/// id "kos_search", listing the matching sections into "kos_search_results".
+pub const SEARCH_JS: &'static str = r##"(function() {
+    var input = document.getElementById("kos_search");
+    var results = document.getElementById("kos_search_results");
+    var index = window.kosbookSearchIndex;
+    if (!input || !results || !index) {
+        return;
+    }
+    function tokenize(text) {
+        return text.toLowerCase().split(/[^\p{L}\p{N}]+/u).filter(
+            function(t) { return t.length > 0; });
+    }
+    // 2 for a whole word, 1 for the start of a word, 0 if not found.
+    function match(word, tokens) {
+        var best = 0;
+        for (var i = 0; i < tokens.length && best < 2; i++) {
+            if (tokens[i] === word) {
+                best = 2;
+            } else if (tokens[i].indexOf(word) === 0) {
+                best = 1;
+            }
+        }
+        return best;
+    }
+    input.addEventListener("input", function() {
+        var query = tokenize(input.value);
+        var found = [];
+        index.sections.forEach(function(section) {
+            var score = 0;
+            var titleTokens = tokenize(section.title);
+            for (var i = 0; i < query.length; i++) {
+                var m = match(query[i], section.tokens);
+                if (m === 0) {
+                    return;
+                }
+                score += m + 2 * match(query[i], titleTokens);
+            }
+            found.push({ section: section, score: score });
+        });
+        found.sort(function(a, b) { return b.score - a.score; });
+        results.innerHTML = "";
+        if (query.length === 0) {
+            return;
+        }
+        found.slice(0, 20).forEach(function(f) {
+            var item = document.createElement("li");
+            var link = document.createElement("a");
+            link.href = "#" + f.section.anchor;
+            link.textContent = f.section.title;
+            item.appendChild(link);
+            if (f.section.chapter !== f.section.title) {
+                var chapter = document.createElement("span");
+                chapter.className = "search_chapter";
+                chapter.textContent = f.section.chapter;
+                item.appendChild(document.createTextNode(" "));
+                item.appendChild(chapter);
+            }
+            results.appendChild(item);
+        });
+    });
+})();
+"##;
+
+// Splits text into lowercase words, as the search script does.
+fn tokenize(text: &str, tokens: &mut Vec<String>, seen: &mut BTreeSet<String>) {
+    for word in text.split(|c: char| !c.is_alphanumeric()) {
+        if word.is_empty() {
+            continue;
+        }
+        let word = word.to_lowercase();
+        if seen.insert(word.clone()) {
+            tokens.push(word);
+        }
+    }
+}
+
+struct Section {
+    title: String,
+    anchor: String,
+    chapter: String,
+    tokens: Vec<String>,
+    seen: BTreeSet<String>
+}
+
+/// Builds the search index of a rendered book, as a script defining
+/// `window.kosbookSearchIndex`. Every heading with an anchor starts a
+/// section, which holds the words of the text up to the next such heading;
+/// first level headings also start a chapter.
+pub fn search_index(events: &Vec<Event>) -> String {
+    let re_id = regex::Regex::new(r#"\sid="([^"]*)""#).unwrap();
+    let mut sections: Vec<Section> = Vec::new();
+    let mut chapter = String::new();
+    // The level, text and anchor of the heading being read, if any.
+    let mut heading: Option<(i32, String, Option<String>)> = None;
+    for event in events.iter() {
+        match *event {
+            Event::Start(Tag::Header(level)) => {
+                heading = Some((level, String::new(), None));
+            },
+            Event::End(Tag::Header(_)) => {
+                let (level, title, anchor) = heading.take().unwrap();
+                let title = title.trim().to_string();
+                match anchor {
+                    Some(anchor) => {
+                        if level == 1 {
+                            chapter = title.clone();
+                        }
+                        let mut section = Section {
+                            title: title.clone(),
+                            anchor: anchor,
+                            chapter: chapter.clone(),
+                            tokens: Vec::new(),
+                            seen: BTreeSet::new()
+                        };
+                        tokenize(&title, &mut section.tokens,
+                                 &mut section.seen);
+                        sections.push(section);
+                    },
+                    // Headings without an anchor cannot be linked to, so
+                    // their text belongs to the current section.
+                    None => {
+                        if let Some(s) = sections.last_mut() {
+                            tokenize(&title, &mut s.tokens, &mut s.seen);
+                        }
+                    }
+                }
+            },
+            Event::InlineHtml(ref html) => {
+                if let Some((_, _, ref mut anchor)) = heading {
+                    if let Some(cap) = re_id.captures(html) {
+                        *anchor = Some(cap.at(1).unwrap().to_string());
+                    }
+                }
+            },
+            Event::Text(ref text) => {
+                if let Some((_, ref mut title, _)) = heading {
+                    title.push_str(text);
+                } else if let Some(s) = sections.last_mut() {
+                    tokenize(text, &mut s.tokens, &mut s.seen);
+                }
+            },
+            _ => ()
+        }
+    }
+    let entries = sections.into_iter().map(|s| {
+        let mut entry = BTreeMap::new();
+        entry.insert("title".to_string(), json::Json::String(s.title));
+        entry.insert("anchor".to_string(), json::Json::String(s.anchor));
+        entry.insert("chapter".to_string(), json::Json::String(s.chapter));
+        entry.insert("tokens".to_string(), json::Json::Array(
+            s.tokens.into_iter().map(json::Json::String).collect()));
+        json::Json::Object(entry)
+    }).collect();
+    let mut index = BTreeMap::new();
+    index.insert("sections".to_string(), json::Json::Array(entries));
+    // Escaped so that the index can be inlined into a script element.
+    "window.kosbookSearchIndex = ".to_string() +
+    &json::Json::Object(index).to_string().replace("</", "<\\/") + ";\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use pulldown_cmark;
+
+    use super::search_index;
+
+    #[test]
+    fn sections() {
+        let text = "Cover text.\n\n\
+                    # <a id=\"c1\">Chapter One</a>\n\n\
+                    Hello, World! hello `code`.\n\n\
+                    ## <a id=\"s\">Sub &lt;/b&gt;</a>\n\n\
+                    Caf\u{e9} 2x.\n\n\
+                    ### Plain\n";
+        let events = pulldown_cmark::Parser::new(text).collect();
+        assert_eq!(search_index(&events),
+                   concat!(r#"window.kosbookSearchIndex = {"sections":["#,
+                           r#"{"anchor":"c1","chapter":"Chapter One","#,
+                           r#""title":"Chapter One","tokens":["chapter","#,
+                           r#""one","hello","world","code"]},"#,
+                           r#"{"anchor":"s","chapter":"Chapter One","#,
+                           r#""title":"Sub <\/b>","tokens":["sub","b","#,
+                           "\"caf\u{e9}\",", r#""2x","plain"]}]};"#, "\n"));
+    }
+}
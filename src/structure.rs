@@ -12,21 +12,40 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use pulldown_cmark;
+use pulldown_cmark::{Event, Tag};
 use rustc_serialize::json;
 
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::prelude::*;
 use std::fs::File;
+use regex;
+
+use assets;
+use bibliography;
+use markdown;
+use template;
+use theme::Theme;
 
 #[derive(Clone, PartialEq, RustcDecodable, RustcEncodable)]
 pub struct Chapter {
     title: String,
-    files: Vec<String>
+    files: Vec<String>,
+    id: Option<String>
 }
 
 #[derive(Clone, PartialEq, RustcDecodable, RustcEncodable)]
 pub struct Part {
     title: String,
-    chapters: Vec<Chapter>
+    chapters: Vec<Chapter>,
+    id: Option<String>
+}
+
+#[derive(Clone, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Stylesheet {
+    path: String,
+    media: Option<String>
 }
 
 #[derive(Clone, PartialEq, RustcDecodable, RustcEncodable)]
@@ -34,101 +53,740 @@ pub struct Structure {
     title: String,
     author: String,
     license: String,
-    parts: Vec<Part>
+    parts: Vec<Part>,
+    toc_depth: Option<usize>,
+    glossary: Option<BTreeMap<String, String>>,
+    bibliography: Option<String>,
+    citation_style: Option<String>,
+    footnotes: Option<String>,
+    metadata: Option<BTreeMap<String, String>>,
+    stylesheets: Option<Vec<Stylesheet>>,
+    inline_styles: Option<bool>,
+    scripts: Option<Vec<String>>,
+    head: Option<String>,
+    search: Option<bool>
+}
+
+fn read_text_file(path: &str, what: &str) -> Result<String, String> {
+    let mut res = String::new();
+    match File::open(path) {
+        Ok(mut fread) => {
+            match fread.read_to_string(&mut res) {
+                Ok(_) => Ok(res),
+                Err(_) => {
+                    Err("Error reading ".to_string() + what + " '" + path +
+                        "'.")
+                }
+            }
+        },
+        Err(_) => {
+            Err("Error opening ".to_string() + what + " '" + path + "'.")
+        }
+    }
 }
 
 impl Structure {
     pub fn from_json(js: &str) -> Result<Structure, json::DecoderError> {
         json::decode::<Structure>(js)
     }
-    pub fn get_title(&self) -> &str { &self.title }
+
+    // Works out the anchor id of every part and chapter: its "id" field if
+    // present, or else a slug of its title made unique by appending a number.
+    fn anchors(&self) -> Result<Anchors, String> {
+        let mut entries = Vec::new();
+        for part in self.parts.iter() {
+            entries.push(("part", &part.title, &part.id));
+            for chap in part.chapters.iter() {
+                entries.push(("chapter", &chap.title, &chap.id));
+            }
+        }
+        // Explicit ids are reserved first, so that slugs never take them.
+        let valid_id = regex::Regex::new("^[-_0-9a-zA-Z]+$").unwrap();
+        let mut used = BTreeSet::new();
+        for &(kind, title, id) in entries.iter() {
+            if let Some(ref id) = *id {
+                if !valid_id.is_match(id) {
+                    return Err("Id '".to_string() + id + "' of " + kind +
+                               " '" + title + "' is invalid. Please only " +
+                               "use hyphens, underscores, digits and ASCII " +
+                               "letters.");
+                }
+                if !used.insert(id.clone()) {
+                    return Err("Id '".to_string() + id + "' of " + kind +
+                               " '" + title + "' is used more than once.");
+                }
+            }
+        }
+        let mut ids = entries.iter().map(|&(_, title, id)| {
+            match *id {
+                Some(ref id) => id.clone(),
+                None => unique_id(&slugify(title), &mut used)
+            }
+        });
+        let mut anchors = Anchors { parts: Vec::new(), chapters: Vec::new() };
+        for part in self.parts.iter() {
+            anchors.parts.push(ids.next().unwrap());
+            anchors.chapters.push(part.chapters.iter().map(|_| {
+                ids.next().unwrap()
+            }).collect());
+        }
+        Ok(anchors)
+    }
+
+    // The template values describing each chapter, for each part.
+    fn chapter_values(&self, anchors: &Anchors)
+    -> Vec<Vec<template::Value>> {
+        self.parts.iter().enumerate().map(|(p, part)| {
+            part.chapters.iter().enumerate().map(|(c, chap)| {
+                entry_value(&format!("{}.{}", p + 1, c + 1), &chap.title,
+                            &anchors.chapters[p][c], None)
+            }).collect()
+        }).collect()
+    }
+
+    // The template values describing each part, including its chapters.
+    fn part_values(&self, anchors: &Anchors,
+                   chapters: &Vec<Vec<template::Value>>)
+    -> Vec<template::Value> {
+        self.parts.iter().enumerate().map(|(p, part)| {
+            entry_value(&format!("{}", p + 1), &part.title, &anchors.parts[p],
+                        Some(chapters[p].clone()))
+        }).collect()
+    }
+
+    fn values(&self, anchors: &Anchors) -> BTreeMap<String, template::Value> {
+        let mut values = BTreeMap::new();
+        for &(name, value) in [("title", &self.title),
+                               ("author", &self.author),
+                               ("license", &self.license)].iter() {
+            values.insert(name.to_string(),
+                          template::Value::Str(value.clone()));
+        }
+        let metadata = self.metadata.as_ref().map_or(BTreeMap::new(), |m| {
+            m.iter().map(|(k, v)| {
+                (k.clone(), template::Value::Str(v.clone()))
+            }).collect()
+        });
+        values.insert("metadata".to_string(), template::Value::Map(metadata));
+        let chapters = self.chapter_values(anchors);
+        values.insert("parts".to_string(),
+                      template::Value::List(self.part_values(anchors,
+                                                             &chapters)));
+        values
+    }
+
+    /// The values which the page template refers to besides those of
+    /// `template_values`: "styles", the markup linking (or inlining) the
+    /// stylesheets, "head", any extra content of the head, and "scripts", the
+    /// markup loading the scripts, and "style", the URL of the last
+    /// stylesheet, kept for themes written before there could be several.
+    /// `style` is the stylesheet given on the command line, if any, which
+    /// comes after those of the structure file.
+    /// For standalone output, stylesheets and scripts are inlined, with the
+    /// local files referred to by the stylesheets embedded as data URIs.
+    pub fn page_values(&self, style: Option<String>,
+                       pipeline: &mut assets::AssetPipeline)
+    -> Result<BTreeMap<String, template::Value>, String> {
+        let standalone = pipeline.standalone();
+        let mut sheets = self.stylesheets.clone().unwrap_or(Vec::new());
+        if let Some(path) = style {
+            sheets.push(Stylesheet { path: path, media: None });
+        } else if self.stylesheets.is_none() {
+            sheets.push(Stylesheet {
+                path: "style.css".to_string(),
+                media: None
+            });
+        }
+        let mut styles = String::new();
+        let mut last_url = String::new();
+        for sheet in sheets.iter() {
+            let media = sheet.media.as_ref().map_or(String::new(), |m| {
+                " media=\"".to_string() + m + "\""
+            });
+            if self.inline_styles.unwrap_or(false) || standalone {
+                let css = match read_text_file(&sheet.path, "stylesheet") {
+                    Ok(x) => x,
+                    Err(e) => { return Err(e); }
+                };
+                let css = if standalone {
+                    match assets::embed_css(&css, &sheet.path) {
+                        Ok(x) => x,
+                        Err(e) => { return Err(e); }
+                    }
+                } else {
+                    css
+                };
+                styles = styles + "    <style" + &media + ">\n" +
+                         css.trim_end() + "\n    </style>\n";
+                last_url = sheet.path.clone();
+            } else {
+                let url = match pipeline.stylesheet(&sheet.path) {
+                    Ok(x) => x,
+                    Err(e) => { return Err(e); }
+                };
+                styles = styles + "    <link rel=\"stylesheet\" " +
+                         "type=\"text/css\" href=\"" + &url + "\"" +
+                         &media + ">\n";
+                last_url = url;
+            }
+        }
+        let mut scripts = String::new();
+        for script in self.scripts.as_ref().unwrap_or(&Vec::new()).iter() {
+            if standalone {
+                let js = match read_text_file(script, "script") {
+                    Ok(x) => x,
+                    Err(e) => { return Err(e); }
+                };
+                scripts = scripts + "<script>\n" + js.trim_end() +
+                          "\n</script>\n";
+            } else {
+                let url = match pipeline.script(script) {
+                    Ok(x) => x,
+                    Err(e) => { return Err(e); }
+                };
+                scripts = scripts + "<script src=\"" + &url +
+                          "\"></script>\n";
+            }
+        }
+        let head = self.head.as_ref().map_or(String::new(), |h| {
+            h.trim_end().to_string() + "\n"
+        });
+        let mut values = BTreeMap::new();
+        values.insert("styles".to_string(), template::Value::Str(styles));
+        values.insert("style".to_string(), template::Value::Str(last_url));
+        values.insert("head".to_string(), template::Value::Str(head));
+        values.insert("scripts".to_string(), template::Value::Str(scripts));
+        Ok(values)
+    }
+
+    /// The files the book is read from: the chapter files, bibliography,
+    /// stylesheets and scripts.
+    pub fn input_files(&self) -> Vec<String> {
+        let mut files = Vec::new();
+        for p in self.parts.iter() {
+            for c in p.chapters.iter() {
+                files.extend(c.files.iter().cloned());
+            }
+        }
+        files.extend(self.bibliography.iter().cloned());
+        for sheet in self.stylesheets.as_ref().unwrap_or(&Vec::new()).iter() {
+            files.push(sheet.path.clone());
+        }
+        files.extend(self.scripts.iter().flat_map(|s| s.iter().cloned()));
+        files
+    }
+
+    /// Whether the HTML output includes a search box and a search index.
+    pub fn search(&self) -> bool {
+        self.search.unwrap_or(false)
+    }
+
+    /// The values which theme templates can refer to: the "title", "author"
+    /// and "license" of the book, its "metadata", and its "parts", each with
+    /// a "number", "title", "anchor" and list of "chapters" (which have a
+    /// "number", "title" and "anchor" too).
+    pub fn template_values(&self)
+    -> Result<BTreeMap<String, template::Value>, String> {
+        match self.anchors() {
+            Ok(anchors) => Ok(self.values(&anchors)),
+            Err(e) => Err(e)
+        }
+    }
 }
 
+// The template value describing a part or chapter.
+fn entry_value(number: &str, title: &str, anchor: &str,
+               chapters: Option<Vec<template::Value>>) -> template::Value {
+    let mut m = BTreeMap::new();
+    m.insert("number".to_string(), template::Value::Str(number.to_string()));
+    m.insert("title".to_string(), template::Value::Str(title.to_string()));
+    m.insert("anchor".to_string(), template::Value::Str(anchor.to_string()));
+    if let Some(chapters) = chapters {
+        m.insert("chapters".to_string(), template::Value::List(chapters));
+    }
+    template::Value::Map(m)
+}
+
+/// The anchor ids of the parts and chapters of a book.
+struct Anchors {
+    parts: Vec<String>,
+    // For each part, the ids of its chapters.
+    chapters: Vec<Vec<String>>
+}
+
+
+/// A heading found in a chapter file.
+struct Heading {
+    level: usize,
+    title: String,
+    id: String
+}
+
+// Turns a heading title into an anchor id made of lowercase letters, digits
+// and hyphens.
+fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if (c.is_whitespace() || c == '-') && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_matches('-').to_string();
+    if slug.is_empty() { "section".to_string() } else { slug }
+}
+
+// Turns a slug into an id which is not in `used`, by appending a number if
+// needed, and adds it to `used`.
+fn unique_id(slug: &str, used: &mut BTreeSet<String>) -> String {
+    let mut id = slug.to_string();
+    let mut n = 1;
+    while used.contains(&id) {
+        id = format!("{}-{}", slug, n);
+        n += 1;
+    }
+    used.insert(id.clone());
+    id
+}
+
+// Maps a line of a processed chunk back to the line of the file it was read
+// from. The non-blank lines of the chunk are matched in order against those
+// of the file; any other line is counted from the last line before it which
+// was found unchanged.
+fn original_line(original: &str, processed: &str, line: usize) -> usize {
+    let lines: Vec<&str> = original.lines().collect();
+    let mut next = 0;
+    let mut last = (0, 0);
+    for (i, l) in processed.lines().take(line).enumerate() {
+        if l.trim().is_empty() {
+            continue;
+        }
+        if let Some(j) = lines[next..].iter().position(|o| *o == l) {
+            next += j + 1;
+            last = (i + 1, next);
+        }
+    }
+    let res = last.1 + (line - last.0);
+    if res > lines.len() { lines.len().max(1) } else { res }
+}
+
+// Gives every heading of a chapter file an anchor, unless it already has
+// one, as in `## <a id="intro">Introduction</a>`. The anchor is a slug of the
+// title, made unique within the book by appending a number.
+fn anchor_headings(chunk: &str, used_ids: &mut BTreeSet<String>)
+-> (String, Vec<Heading>) {
+    let re_anchored = regex::Regex::new(r#"^<a id="([^"]*)">(.*)</a>$"#)
+                      .unwrap();
+    let mut headings = Vec::new();
+    let mut res = String::with_capacity(chunk.len());
+    let mut last = 0;
+    for (level, start, end) in markdown::headings(chunk).into_iter() {
+        let title = &chunk[start..end];
+        if let Some(a) = re_anchored.captures(title) {
+            let id = a.at(1).unwrap().to_string();
+            used_ids.insert(id.clone());
+            headings.push(Heading {
+                level: level,
+                title: a.at(2).unwrap().to_string(),
+                id: id
+            });
+            continue;
+        }
+        let id = unique_id(&slugify(title), used_ids);
+        res.push_str(&chunk[last..start]);
+        res.push_str(&format!("<a id=\"{}\">{}</a>", id, title));
+        last = end;
+        headings.push(Heading {
+            level: level,
+            title: title.to_string(),
+            id: id
+        });
+    }
+    res.push_str(&chunk[last..]);
+    (res, headings)
+}
+
+// Gives each glossary term an anchor id, made unique by appending a number.
+fn glossary_ids(glossary: &BTreeMap<String, String>)
+-> BTreeMap<String, String> {
+    let mut used = BTreeSet::new();
+    glossary.keys().map(|term| {
+        let id = unique_id(&("kos_gloss_".to_string() + &slugify(term)),
+                           &mut used);
+        (term.clone(), id)
+    }).collect()
+}
+
+// Links the first use of each glossary term in a chapter to its definition,
+// with the definition as a tooltip. `re` has a group for each term in `terms`,
+// `ids` holds the anchor of each term and `linked` the terms already linked
+// in the chapter. Only plain text, as found by the CommonMark parser, is
+// changed: code, markup, template tags, headings, and the text and
+// destinations of links and images are left alone.
+fn link_glossary_terms(chunk: &str, re: &regex::Regex, terms: &Vec<&str>,
+                       glossary: &BTreeMap<String, String>,
+                       ids: &BTreeMap<String, String>,
+                       linked: &mut BTreeSet<String>) -> String {
+    let re_tag = regex::Regex::new(r"\{\{.*?\}\}|\{%.*?%\}").unwrap();
+    let tags: Vec<(usize, usize)> = re_tag.find_iter(chunk).collect();
+    let mut opts = pulldown_cmark::Options::empty();
+    opts.insert(pulldown_cmark::OPTION_ENABLE_TABLES);
+    opts.insert(pulldown_cmark::OPTION_ENABLE_FOOTNOTES);
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    // How many headings, links, images and code elements the parser is in.
+    let mut skip = 0;
+    for event in pulldown_cmark::Parser::new_ext(chunk, opts) {
+        match event {
+            Event::Start(Tag::Header(_)) | Event::Start(Tag::Link(_, _)) |
+            Event::Start(Tag::Image(_, _)) | Event::Start(Tag::Code) |
+            Event::Start(Tag::CodeBlock(_)) => { skip += 1; },
+            Event::End(Tag::Header(_)) | Event::End(Tag::Link(_, _)) |
+            Event::End(Tag::Image(_, _)) | Event::End(Tag::Code) |
+            Event::End(Tag::CodeBlock(_)) => { skip -= 1; },
+            // Text with escapes or entities is not a slice of the source,
+            // and is left alone.
+            Event::Text(Cow::Borrowed(text)) if skip == 0 => {
+                if let Some((s, e)) = markdown::source_range(chunk, text) {
+                    match ranges.last_mut() {
+                        Some(last) if last.1 == s => { last.1 = e; },
+                        _ => { ranges.push((s, e)); }
+                    }
+                }
+            },
+            _ => ()
+        }
+    }
+    let mut res = String::with_capacity(chunk.len());
+    let mut last = 0;
+    for (start, end) in ranges.into_iter() {
+        for cap in re.captures_iter(&chunk[start..end]) {
+            let (s, e) = cap.pos(0).unwrap();
+            let (s, e) = (start + s, start + e);
+            if tags.iter().any(|&(ts, te)| s < te && e > ts) {
+                continue;
+            }
+            let term = match (1..cap.len()).find(|&i| cap.pos(i).is_some()) {
+                Some(i) => terms[i - 1],
+                None => { continue; }
+            };
+            if !linked.insert(term.to_string()) {
+                continue;
+            }
+            res.push_str(&chunk[last..s]);
+            res.push_str(&format!(
+                "<a class=\"glossary_term\" href=\"#{}\" title=\"{}\">{}</a>",
+                ids[term], glossary[term].replace("\"", "&quot;"),
+                &chunk[s..e]));
+            last = e;
+        }
+    }
+    res.push_str(&chunk[last..]);
+    res
+}
+
+// Footnote labels are kept to hyphens, digits and ASCII letters once they are
+// namespaced. Any other byte, underscores included, is written as `_` and two
+// hex digits, so that different labels never end up the same.
+fn escape_footnote_label(label: &str) -> String {
+    let mut res = String::with_capacity(label.len());
+    for &b in label.as_bytes().iter() {
+        if b == b'-' || (b as char).is_ascii_alphanumeric() {
+            res.push(b as char);
+        } else {
+            res.push_str(&format!("_{:02x}", b));
+        }
+    }
+    res
+}
+
+/// The label of a footnote as it was written, given the label it has once
+/// it is namespaced to its file.
+pub fn footnote_label(name: &str) -> String {
+    let re = regex::Regex::new(r"^kos_fn_\d+_\d+_\d+_(.*)$").unwrap();
+    let escaped = match re.captures(name) {
+        Some(cap) => cap.at(1).unwrap().to_string(),
+        None => { return name.to_string(); }
+    };
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_str();
+    while !rest.is_empty() {
+        let hex = if rest.starts_with('_') && rest.len() >= 3 {
+            u8::from_str_radix(&rest[1..3], 16).ok()
+        } else {
+            None
+        };
+        match hex {
+            Some(b) => {
+                bytes.push(b);
+                rest = &rest[3..];
+            },
+            None => {
+                bytes.push(rest.as_bytes()[0]);
+                rest = &rest[1..];
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Gives the footnotes of a chapter file labels starting with `prefix`, so that
+// different files may use the same labels. If `collect` is true, footnote
+// definitions (with their indented continuation lines) are also taken out of
+// the content and returned separately.
+fn namespace_footnotes(chunk: &str, prefix: &str, collect: bool)
+-> (String, Vec<String>) {
+    let re = regex::Regex::new(r"\[\^([^\]]+)\]").unwrap();
+    let content = markdown::map_text_segments(chunk, |text| {
+        re.replace_all(text, |cap: &regex::Captures| {
+            "[^".to_string() + prefix +
+            &escape_footnote_label(cap.at(1).unwrap()) + "]"
+        })
+    });
+    if !collect {
+        return (content, Vec::new());
+    }
+    let re_def = regex::Regex::new(r"^ {0,3}\[\^[^\]]+\]:").unwrap();
+    let segs = markdown::segments(&content);
+    let mut kept = Vec::new();
+    let mut defs = Vec::new();
+    let mut current: Option<Vec<&str>> = None;
+    let mut after_blank = false;
+    let mut pos = 0;
+    for line in content.split('\n') {
+        let line_start = pos;
+        pos += line.len() + 1;
+        let in_text = segs.iter().any(|&(kind, s, e)| {
+            kind == markdown::SegmentKind::Text && s <= line_start &&
+            line_start < e
+        });
+        if in_text && re_def.is_match(line) {
+            if let Some(def) = current.take() {
+                defs.push(def.join("\n").trim_end().to_string());
+            }
+            current = Some(vec![line]);
+            after_blank = false;
+            continue;
+        }
+        if let Some(ref mut def) = current {
+            let indented = line.starts_with("    ") || line.starts_with('\t');
+            if line.trim().is_empty() || indented || !after_blank {
+                after_blank = line.trim().is_empty();
+                def.push(line);
+                continue;
+            }
+        }
+        if let Some(def) = current.take() {
+            defs.push(def.join("\n").trim_end().to_string());
+        }
+        kept.push(line);
+    }
+    if let Some(def) = current.take() {
+        defs.push(def.join("\n").trim_end().to_string());
+    }
+    (kept.join("\n"), defs)
+}
 
 #[derive(Clone, PartialEq)]
 pub struct Content {
-    pub chunks: Vec<String>
+    pub chunks: Vec<String>,
+    // Where each chunk came from: a file path, or a description in angle
+    // brackets for generated content.
+    pub sources: Vec<String>,
+    // The number of the chapter each chunk belongs to, as in "2.1", or an
+    // empty string for chunks outside chapters.
+    pub chapters: Vec<String>,
+    // The titles and anchors of the generated chapters listed at the end of
+    // the TOC, such as the glossary.
+    toc_extras: Vec<(String, String)>,
+    // The text each chunk was read from, before any processing, or an empty
+    // string for generated content.
+    originals: Vec<String>
 }
 
 impl Content {
-    fn build_title_page(st: &Structure) -> Result<String, String> {
-        let book_header =
-            r#"<div class="book_cover">"#.to_string() +
-            r#"<div class="book_author">"# +
-            &st.author +
-            r#"</div>"# + 
-            r#"<div class="book_title">"# +
-            r#"<a id="kos_book_title">"# +
-            &st.title +
-            "</a></div>" +
-            r#"<div class="book_license">(C) "# +
-            &st.author +
-            " - " +
-            &st.license +
-            "</div></div>\n\n";
-        Ok(book_header)
-    }
-
-    fn build_toc(st: &Structure) -> Result<String, String> {
+    // The entries of the TOC. `headings` holds the headings of each chapter,
+    // by chapter number.
+    fn build_toc(&self, st: &Structure, anchors: &Anchors,
+                 headings: &BTreeMap<String, Vec<Heading>>) -> String {
+        let depth = st.toc_depth.unwrap_or(1);
         let mut toc = String::new();
-        toc = toc + r#"<div class="toc">"# + "\n\n";
         let mut part_index = 1;
         for part in st.parts.iter() {
             let part_link = format!(
-                "- **[{0} {1}](#kos_ref_part_{0})**\n\n", part_index, part.title);
+                "- **[{0} {1}](#{2})**\n\n", part_index, part.title,
+                anchors.parts[part_index - 1]);
             toc = toc + &part_link;
             let mut chap_index = 1;
             for chap in part.chapters.iter() {
                 let chap_link = format!(
-                    "   - *[{0}.{1} {2}](#kos_ref_chap_{0}_{1})*\n\n",
-                    part_index, chap_index, chap.title);
+                    "   - *[{0}.{1} {2}](#{3})*\n\n",
+                    part_index, chap_index, chap.title,
+                    anchors.chapters[part_index - 1][chap_index - 1]);
                 toc = toc + &chap_link;
+                let hs = headings.get(&format!("{}.{}", part_index,
+                                               chap_index));
                 chap_index += 1;
+                let hs = match hs {
+                    Some(x) if depth >= 2 => x,
+                    _ => { continue; }
+                };
+                // Levels of the enclosing headings, to nest the list items.
+                let mut open: Vec<usize> = Vec::new();
+                for h in hs.iter().filter(|h| h.level <= depth) {
+                    while open.last().map_or(false, |&l| l >= h.level) {
+                        open.pop();
+                    }
+                    toc = toc + &" ".repeat(5 + 2 * open.len()) +
+                          &format!("- [{}](#{})\n\n", h.title, h.id);
+                    open.push(h.level);
+                }
             }
             part_index += 1;
         }
-        toc = toc + "</div>\n\n";
-        Ok(toc)
+        for &(ref title, ref anchor) in self.toc_extras.iter() {
+            toc = toc + "- **[" + title + "](#" + anchor + ")**\n\n";
+        }
+        toc
     }
 
-    fn build_chunks(st: &Structure) -> Result<Vec<String>, String> {
-        let mut chunks = Vec::new();
-        // Book cover first...
-        match Content::build_title_page(st) {
-            Ok(tp) => { chunks.push(tp); },
-            Err(e) => { return Err(e); }
+    /// Lists a generated chapter, such as the index, at the end of the TOC.
+    pub fn add_toc_entry(&mut self, title: &str, anchor: &str) {
+        self.toc_extras.push((title.to_string(), anchor.to_string()));
+    }
+
+    fn build_glossary(glossary: &BTreeMap<String, String>,
+                      ids: &BTreeMap<String, String>) -> String {
+        let mut terms: Vec<(&String, &String)> = glossary.iter().collect();
+        terms.sort_by_key(|&(t, _)| t.to_lowercase());
+        let mut res = "# <a id=\"kos_glossary\">Glossary</a>\n\n".to_string() +
+                      "<div class=\"book_glossary\">\n\n";
+        for (term, definition) in terms.into_iter() {
+            res = res + "- <a id=\"" + &ids[term] + "\"></a>**" + term +
+                  "**: " + definition + "\n\n";
         }
-        // Then TOC...
-        match Content::build_toc(st) {
-            Ok(toc) => { chunks.push(toc); },
+        res + "</div>\n\n"
+    }
+
+    /// Adds a chunk of generated content at the end of the book.
+    pub fn push_chunk(&mut self, source: &str, chapter: &str, chunk: String) {
+        self.chunks.push(chunk);
+        self.sources.push(source.to_string());
+        self.chapters.push(chapter.to_string());
+        self.originals.push(String::new());
+    }
+
+    fn push_file_chunk(&mut self, source: &str, chapter: &str, chunk: String,
+                       original: String) {
+        self.push_chunk(source, chapter, chunk);
+        *self.originals.last_mut().unwrap() = original;
+    }
+
+    fn build_chunks(&mut self, st: &Structure, theme: &Theme)
+    -> Result<(), String> {
+        let anchors = match st.anchors() {
+            Ok(x) => x,
+            Err(e) => { return Err(e); }
+        };
+        let values = st.values(&anchors);
+        let chapter_values = st.chapter_values(&anchors);
+        let part_values = st.part_values(&anchors, &chapter_values);
+        // Book cover first...
+        match theme.render("cover.md", &values) {
+            Ok(tp) => { self.push_chunk("<cover>", "", tp); },
             Err(e) => { return Err(e); }
         }
+        // Then TOC, which is filled in once the rules have been applied...
+        self.push_chunk("<toc>", "", String::new());
         // Then parts and chapters.
+        let empty_glossary = BTreeMap::new();
+        let glossary = st.glossary.as_ref().unwrap_or(&empty_glossary);
+        let mut lowercase_terms = BTreeSet::new();
+        for t in glossary.keys() {
+            if t.trim().is_empty() {
+                return Err("Glossary terms must not be empty.".to_string());
+            }
+            if !lowercase_terms.insert(t.to_lowercase()) {
+                return Err("Glossary term '".to_string() + t + "' is " +
+                           "defined more than once.");
+            }
+        }
+        // One group per term, so that a match tells which term it is.
+        let mut terms: Vec<&str> = glossary.keys().map(|t| t.as_str())
+                                           .collect();
+        let alternation = markdown::word_bounded_alternation(&mut terms);
+        let gloss_ids = glossary_ids(glossary);
+        let mut bib = match st.bibliography {
+            Some(ref f) => {
+                let mut res = String::new();
+                match File::open(f) {
+                    Ok(mut fread) => {
+                        match fread.read_to_string(&mut res) {
+                            Ok(_) => (),
+                            Err(_) => {
+                                return Err("Error reading file ".to_string() +
+                                           f + "!\n");
+                            }
+                        }
+                    },
+                    Err(_) => {
+                        return Err("Error reading file ".to_string() + f +
+                                   "!\n");
+                    }
+                }
+                let style = st.citation_style.as_ref().map_or("numeric", |s| {
+                    s.as_str()
+                });
+                match bibliography::Bibliography::from_csl_json(&res, style) {
+                    Ok(x) => Some(x),
+                    Err(e) => { return Err(e); }
+                }
+            },
+            None => None
+        };
+        let re_terms = if glossary.is_empty() {
+            None
+        } else {
+            Some(regex::Regex::new(&("(?i)".to_string() + &alternation))
+                 .unwrap())
+        };
+        let footnotes = st.footnotes.as_ref().map_or("inline", |f| f.as_str());
+        if footnotes != "inline" && footnotes != "chapter" &&
+           footnotes != "endnotes" {
+            return Err("Footnotes placement '".to_string() + footnotes +
+                       "' is invalid. Please use 'inline', 'chapter' or " +
+                       "'endnotes'.");
+        }
+        let mut notes = String::new();
         let mut part_index = 1;
         for part in st.parts.iter() {
-            let part_header = 
-                r#"<div class="part_"#.to_string() + // Open part div
-                &format!("{}", part_index) +
-                r#"">"# + "\n\n" +
-                r#"<div class="part_title">"# + // Part title div
-                r#"<a id="kos_ref_part_"# +
-                &format!("{}", part_index) +
-                r#"">"# +
-                &part.title +
-                "</a></div>\n\n"; // Close part title div
-            chunks.push(part_header);
+            let mut part_vars = values.clone();
+            part_vars.insert("part".to_string(),
+                             part_values[part_index - 1].clone());
+            let part_header = match theme.render("part_header.md",
+                                                 &part_vars) {
+                Ok(x) => x,
+                Err(e) => { return Err(e); }
+            };
+            self.push_chunk(&format!("<part {} header>", part_index), "",
+                            part_header);
             let mut chap_index = 1;
             for chap in part.chapters.iter() {
-                let chap_header = 
-                    r#"# <a id="kos_ref_chap_"#.to_string() +
-                    &format!("{}", part_index) +
-                    "_" +
-                    &format!("{}", chap_index) +
-                    r#""> "# +
-                    &chap.title +
-                    "</a>\n\n";
-                chunks.push(chap_header);
-                for f in chap.files.iter() {
+                let chap_number = format!("{}.{}", part_index, chap_index);
+                let mut chap_vars = part_vars.clone();
+                chap_vars.insert(
+                    "chapter".to_string(),
+                    chapter_values[part_index - 1][chap_index - 1].clone());
+                let chap_header = match theme.render("chapter_header.md",
+                                                     &chap_vars) {
+                    Ok(x) => x,
+                    Err(e) => { return Err(e); }
+                };
+                self.push_chunk(&format!("<chapter {} header>", chap_number),
+                                &chap_number, chap_header);
+                let mut linked = BTreeSet::new();
+                let mut chap_notes = Vec::new();
+                for (file_index, f) in chap.files.iter().enumerate() {
                     let file_content = match File::open(f) {
                         Ok(mut fread) => {
                             let mut res = String::new();
@@ -147,24 +805,175 @@ impl Content {
                                 "Error reading file ".to_string() + f + "!\n");
                         }
                     };
-                    chunks.push(file_content);
+                    let original = file_content.clone();
+                    let prefix = format!("kos_fn_{}_{}_",
+                                         chap_number.replace('.', "_"),
+                                         file_index + 1);
+                    let (file_content, mut defs) = namespace_footnotes(
+                        &file_content, &prefix, footnotes != "inline");
+                    chap_notes.append(&mut defs);
+                    let file_content = match bib {
+                        Some(ref mut b) => match b.cite(&file_content, f) {
+                            Ok(x) => x,
+                            Err(e) => { return Err(e); }
+                        },
+                        None => file_content
+                    };
+                    let file_content = match re_terms {
+                        Some(ref re) => {
+                            link_glossary_terms(&file_content, re, &terms,
+                                                glossary, &gloss_ids,
+                                                &mut linked)
+                        },
+                        None => file_content
+                    };
+                    self.push_file_chunk(f, &chap_number, file_content,
+                                         original);
+                }
+                if !chap_notes.is_empty() {
+                    if footnotes == "chapter" {
+                        self.push_chunk(
+                            &format!("<chapter {} footnotes>", chap_number),
+                            &chap_number,
+                            "<div class=\"footnotes\">\n\n".to_string() +
+                            &chap_notes.join("\n\n") + "\n\n</div>\n\n");
+                    } else {
+                        notes = notes + "## " + &chap_number + " " +
+                                &chap.title + "\n\n" +
+                                &chap_notes.join("\n\n") + "\n\n";
+                    }
                 }
                 chap_index += 1;
             }
-            chunks.push("\n\n</div>\n\n".to_string()); // Close part div
+            // Close part div
+            let part_footer = match theme.render("part_footer.md",
+                                                 &part_vars) {
+                Ok(x) => x,
+                Err(e) => { return Err(e); }
+            };
+            self.push_chunk(&format!("<part {} footer>", part_index), "",
+                            part_footer);
             part_index += 1;
         }
-        Ok(chunks)
+        if !notes.is_empty() {
+            self.push_chunk("<notes>", "",
+                            "# <a id=\"kos_notes\">Notes</a>\n\n".to_string() +
+                            "<div class=\"book_notes\">\n\n" + &notes +
+                            "</div>\n\n");
+            self.add_toc_entry("Notes", "kos_notes");
+        }
+        if bib.as_ref().map_or(false, |b| b.has_citations()) {
+            self.push_chunk("<references>", "",
+                            bib.as_ref().unwrap().references());
+            self.add_toc_entry("References", "kos_references");
+        }
+        if !glossary.is_empty() {
+            self.push_chunk("<glossary>", "",
+                            Content::build_glossary(glossary, &gloss_ids));
+            self.add_toc_entry("Glossary", "kos_glossary");
+        }
+        Ok(())
     }
 
-    pub fn from_structure(st: &Structure) -> Result<Content, String> {
-        let chunks = Content::build_chunks(st);
-        Ok(Content {
-            chunks: match chunks {
-                Ok(c) => c,
-                Err(e) => { return Err(e); }
+    /// Gives the headings of the chapter files anchors and fills in the TOC.
+    /// This is done once the rules have been applied and the variables
+    /// substituted, so that rules see the headings as they were written.
+    pub fn finish(&mut self, st: &Structure, theme: &Theme)
+    -> Result<(), String> {
+        let anchors = match st.anchors() {
+            Ok(x) => x,
+            Err(e) => { return Err(e); }
+        };
+        let mut used_ids: BTreeSet<String> = anchors.parts.iter()
+            .chain(anchors.chapters.iter().flat_map(|c| c.iter()))
+            .cloned().collect();
+        let mut headings: BTreeMap<String, Vec<Heading>> = BTreeMap::new();
+        for ((chunk, source), chapter) in self.chunks.iter_mut()
+                                          .zip(self.sources.iter())
+                                          .zip(self.chapters.iter()) {
+            if source.starts_with('<') {
+                continue;
             }
-        })
+            let (new_chunk, mut hs) = anchor_headings(&chunk, &mut used_ids);
+            headings.entry(chapter.clone()).or_insert(Vec::new())
+                    .append(&mut hs);
+            chunk.clear();
+            chunk.push_str(&new_chunk);
+        }
+        let mut toc_vars = st.values(&anchors);
+        toc_vars.insert("entries".to_string(), template::Value::Str(
+            self.build_toc(st, &anchors, &headings)));
+        let toc = match theme.render("toc.md", &toc_vars) {
+            Ok(x) => x,
+            Err(e) => { return Err(e); }
+        };
+        if let Some(i) = self.sources.iter().position(|s| s == "<toc>") {
+            self.chunks[i] = toc;
+        }
+        Ok(())
+    }
+
+    /// Content without any chunks.
+    pub fn new() -> Content {
+        Content {
+            chunks: Vec::new(),
+            sources: Vec::new(),
+            chapters: Vec::new(),
+            toc_extras: Vec::new(),
+            originals: Vec::new()
+        }
+    }
+
+    pub fn from_structure(st: &Structure, theme: &Theme)
+    -> Result<Content, String> {
+        let mut content = Content::new();
+        match content.build_chunks(st, theme) {
+            Ok(_) => Ok(content),
+            Err(e) => Err(e)
+        }
+    }
+
+    // The index of the chunk at a byte offset of `to_single_string()`, and
+    // the offset within that chunk.
+    fn chunk_at(&self, offset: usize) -> Option<(usize, usize)> {
+        let mut start = 0;
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            start += 2;
+            if offset < start + chunk.len() {
+                let within = if offset > start { offset - start } else { 0 };
+                return Some((i, within));
+            }
+            start += chunk.len();
+        }
+        None
+    }
+
+    /// The source of the chunk at a byte offset of `to_single_string()`.
+    pub fn source_at(&self, offset: usize) -> &str {
+        match self.chunk_at(offset) {
+            Some((i, _)) => &self.sources[i],
+            None => self.sources.last().map_or("", |s| s.as_str())
+        }
+    }
+
+    /// The source of the chunk at a byte offset of `to_single_string()`, and
+    /// the line of the source the offset is on. For files, this is the line
+    /// of the file as it was read, before the rules changed it.
+    pub fn location_at(&self, offset: usize) -> (&str, usize) {
+        let (i, within) = match self.chunk_at(offset) {
+            Some(x) => x,
+            None => {
+                return (self.sources.last().map_or("", |s| s.as_str()), 1);
+            }
+        };
+        let chunk = &self.chunks[i];
+        let line = chunk.as_bytes()[..within].iter()
+                   .filter(|&&b| b == b'\n').count() + 1;
+        if self.originals[i].is_empty() {
+            (&self.sources[i], line)
+        } else {
+            (&self.sources[i], original_line(&self.originals[i], chunk, line))
+        }
     }
 
     pub fn to_single_string(&self) -> String {
@@ -173,3 +982,212 @@ impl Content {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::env;
+    use std::fs::File;
+    use std::io::prelude::*;
+
+    use regex;
+
+    use assets::AssetPipeline;
+    use markdown;
+    use template::Value;
+
+    use super::{anchor_headings, footnote_label, glossary_ids,
+                link_glossary_terms, namespace_footnotes, slugify, Content,
+                Heading, Structure};
+
+    fn structure(parts: &str, toc_depth: usize) -> Structure {
+        Structure::from_json(&format!(
+            r#"{{"title": "T", "author": "A", "license": "L",
+                "toc_depth": {}, "parts": {}}}"#, toc_depth, parts)).unwrap()
+    }
+
+    #[test]
+    fn heading_anchors() {
+        let mut used = BTreeSet::new();
+        used.insert("intro".to_string());
+        let (chunk, headings) = anchor_headings(
+            "## Intro ##\n\nSetext\n======\n\n> ### Quoted\n\n\
+             - #### In a list\n\n## <a id=\"x\">Kept</a>\n\n\
+             ```\n## Code\n```\n", &mut used);
+        assert_eq!(chunk,
+                   "## <a id=\"intro-1\">Intro</a> ##\n\n\
+                    <a id=\"setext\">Setext</a>\n======\n\n\
+                    > ### <a id=\"quoted\">Quoted</a>\n\n\
+                    - #### <a id=\"in-a-list\">In a list</a>\n\n\
+                    ## <a id=\"x\">Kept</a>\n\n```\n## Code\n```\n");
+        let found: Vec<(usize, &str, &str)> = headings.iter().map(|h| {
+            (h.level, h.title.as_str(), h.id.as_str())
+        }).collect();
+        assert_eq!(found, vec![(2, "Intro", "intro-1"), (1, "Setext", "setext"),
+                               (3, "Quoted", "quoted"),
+                               (4, "In a list", "in-a-list"),
+                               (2, "Kept", "x")]);
+        assert!(used.contains("x") && used.contains("quoted"));
+    }
+
+    // The page values of a book with the given extra structure fields.
+    fn page(fields: &str, style: Option<&str>)
+    -> Result<BTreeMap<String, String>, String> {
+        let st = Structure::from_json(&format!(
+            r#"{{"title": "T", "author": "A", "license": "L",
+                "parts": []{}}}"#, fields)).unwrap();
+        let mut pipeline = AssetPipeline::new("book.html", false, false);
+        st.page_values(style.map(|s| s.to_string()), &mut pipeline)
+          .map(|values| values.into_iter().map(|(k, v)| {
+              (k, match v { Value::Str(s) => s, _ => String::new() })
+          }).collect())
+    }
+
+    #[test]
+    fn page_values() {
+        let p = page("", None).unwrap();
+        assert_eq!(p["styles"], "    <link rel=\"stylesheet\" \
+                                 type=\"text/css\" href=\"style.css\">\n");
+        assert_eq!(p["style"], "style.css");
+        assert_eq!(p["head"], "");
+        assert_eq!(p["scripts"], "");
+        let p = page(r#", "stylesheets": [{"path": "a.css", "media": "print"}],
+                       "scripts": ["a.js"], "head": "<meta name=\"x\">\n\n""#,
+                     Some("b.css")).unwrap();
+        assert_eq!(p["styles"], "    <link rel=\"stylesheet\" \
+                                 type=\"text/css\" href=\"a.css\" \
+                                 media=\"print\">\n    \
+                                 <link rel=\"stylesheet\" \
+                                 type=\"text/css\" href=\"b.css\">\n");
+        assert_eq!(p["style"], "b.css");
+        assert_eq!(p["head"], "<meta name=\"x\">\n");
+        assert_eq!(p["scripts"], "<script src=\"a.js\"></script>\n");
+        // An empty list of stylesheets links none.
+        assert_eq!(page(r#", "stylesheets": []"#, None).unwrap()["styles"], "");
+    }
+
+    #[test]
+    fn inline_styles() {
+        let css = env::temp_dir().join("kosbook_inline.css");
+        File::create(&css).and_then(|mut f| f.write_all(b"p { x: y; }\n\n"))
+                          .unwrap();
+        let css = css.to_string_lossy().replace('\\', "/");
+        let p = page(&format!(r#", "stylesheets": [{{"path": "{}"}}],
+                                 "inline_styles": true"#, css), None).unwrap();
+        assert_eq!(p["styles"], "    <style>\np { x: y; }\n    </style>\n");
+        assert_eq!(p["style"], css);
+        assert!(page(r#", "stylesheets": [{"path": "no_such.css"}],
+                       "inline_styles": true"#, None).is_err());
+    }
+
+    #[test]
+    fn slug_collisions() {
+        assert_eq!(slugify("Getting  started -- now!"), "getting-started-now");
+        assert_eq!(slugify("!!!"), "section");
+        let st = structure(r#"[{"title": "Intro", "chapters": [
+            {"title": "Intro", "files": []},
+            {"title": "Setup", "files": [], "id": "intro"}]}]"#, 1);
+        let anchors = st.anchors().unwrap();
+        assert_eq!(anchors.parts, vec!["intro-1"]);
+        assert_eq!(anchors.chapters, vec![vec!["intro-2", "intro"]]);
+        let mut used: BTreeSet<String> = anchors.parts.into_iter().collect();
+        used.extend(anchors.chapters.into_iter().flat_map(|c| c));
+        let (chunk, _) = anchor_headings("# Intro\n", &mut used);
+        assert_eq!(chunk, "# <a id=\"intro-3\">Intro</a>\n");
+        for bad in [r#""id": "a b""#, r#""id": "intro""#].iter() {
+            let st = structure(&format!(r#"[{{"title": "P", "id": "intro",
+                "chapters": [{{"title": "C", "files": [], {}}}]}}]"#, bad), 1);
+            assert!(st.anchors().is_err(), "{}", bad);
+        }
+        let mut glossary = BTreeMap::new();
+        glossary.insert("C".to_string(), String::new());
+        glossary.insert("C++".to_string(), String::new());
+        let ids = glossary_ids(&glossary);
+        assert_eq!(ids["C"], "kos_gloss_c");
+        assert_eq!(ids["C++"], "kos_gloss_c-1");
+    }
+
+    #[test]
+    fn toc_depth() {
+        let parts = r#"[{"title": "P", "chapters": [
+            {"title": "C", "files": [], "id": "c"}], "id": "p"}]"#;
+        let heading = |level: usize, title: &str| Heading {
+            level: level,
+            title: title.to_string(),
+            id: slugify(title)
+        };
+        let mut headings = BTreeMap::new();
+        headings.insert("1.1".to_string(),
+                        vec![heading(2, "A"), heading(3, "B"),
+                             heading(4, "Deep"), heading(2, "D")]);
+        let content = Content::new();
+        let toc = |depth: usize| {
+            let st = structure(parts, depth);
+            content.build_toc(&st, &st.anchors().unwrap(), &headings)
+        };
+        assert_eq!(toc(1), "- **[1 P](#p)**\n\n   - *[1.1 C](#c)*\n\n");
+        assert_eq!(toc(3), "- **[1 P](#p)**\n\n   - *[1.1 C](#c)*\n\n\
+                            \x20    - [A](#a)\n\n\
+                            \x20      - [B](#b)\n\n\
+                            \x20    - [D](#d)\n\n");
+    }
+
+    #[test]
+    fn glossary_first_use() {
+        let mut glossary = BTreeMap::new();
+        glossary.insert("borrow".to_string(),
+                        "Taking a \"reference\"".to_string());
+        glossary.insert("borrow checker".to_string(), "Checks".to_string());
+        glossary.insert("C++".to_string(), "A language".to_string());
+        let ids = glossary_ids(&glossary);
+        let mut terms: Vec<&str> = glossary.keys().map(|t| t.as_str())
+                                           .collect();
+        let re = regex::Regex::new(
+            &("(?i)".to_string() +
+              &markdown::word_bounded_alternation(&mut terms))).unwrap();
+        let link = |chunk: &str, linked: &mut BTreeSet<String>| {
+            link_glossary_terms(chunk, &re, &terms, &glossary, &ids, linked)
+        };
+        let mut linked = BTreeSet::new();
+        let chunk = "# Borrow\n\n`borrow`, [borrow](b.md), {{ borrow }}, \
+                     borrowing, C++x and the Borrow Checker.\n";
+        assert_eq!(link(chunk, &mut linked),
+                   "# Borrow\n\n`borrow`, [borrow](b.md), {{ borrow }}, \
+                    borrowing, <a class=\"glossary_term\" \
+                    href=\"#kos_gloss_c\" title=\"A language\">C++</a>x and \
+                    the <a class=\"glossary_term\" \
+                    href=\"#kos_gloss_borrow-checker\" title=\"Checks\">\
+                    Borrow Checker</a>.\n");
+        assert_eq!(link("A borrow, another borrow, C++.\n", &mut linked),
+                   "A <a class=\"glossary_term\" href=\"#kos_gloss_borrow\" \
+                    title=\"Taking a &quot;reference&quot;\">borrow</a>, \
+                    another borrow, C++.\n");
+        // Each chapter starts afresh.
+        let mut linked = BTreeSet::new();
+        assert!(link("C++\n", &mut linked).contains("kos_gloss_c"));
+    }
+
+    #[test]
+    fn footnotes() {
+        let chunk = "A[^a b] B[^a_b] `[^c]`\n\n[^a b]: One.\n    more\n\n\
+                     [^a_b]: Two.\nlazy\n\nAfter\n";
+        let (content, defs) = namespace_footnotes(chunk, "kos_fn_1_2_3_",
+                                                  false);
+        assert_eq!(content,
+                   "A[^kos_fn_1_2_3_a_20b] B[^kos_fn_1_2_3_a_5fb] `[^c]`\n\n\
+                    [^kos_fn_1_2_3_a_20b]: One.\n    more\n\n\
+                    [^kos_fn_1_2_3_a_5fb]: Two.\nlazy\n\nAfter\n");
+        assert!(defs.is_empty());
+        let (content, defs) = namespace_footnotes(chunk, "kos_fn_1_2_3_",
+                                                  true);
+        assert_eq!(content,
+                   "A[^kos_fn_1_2_3_a_20b] B[^kos_fn_1_2_3_a_5fb] `[^c]`\n\n\
+                    After\n");
+        assert_eq!(defs, vec!["[^kos_fn_1_2_3_a_20b]: One.\n    more",
+                              "[^kos_fn_1_2_3_a_5fb]: Two.\nlazy"]);
+        assert_eq!(footnote_label("kos_fn_1_2_3_a_20b"), "a b");
+        assert_eq!(footnote_label("kos_fn_1_2_3_a_5fb"), "a_b");
+        assert_eq!(footnote_label("kos_fn_1_2_3_caf_c3_a9"), "caf\u{e9}");
+        assert_eq!(footnote_label("other_label"), "other_label");
+    }
+}
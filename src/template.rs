@@ -0,0 +1,857 @@
+// Copyright 2016 Dario Domizioli
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use regex;
+
+use markdown;
+
+/// A value which can be referred to from a template.
+#[derive(Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        match *self {
+            Value::Str(ref s) => !s.is_empty(),
+            Value::List(ref l) => !l.is_empty(),
+            Value::Map(ref m) => !m.is_empty(),
+        }
+    }
+
+    /// The text a value is substituted with when output as it is. Lists are
+    /// separated by blank lines, and maps are listed as `key: value` entries
+    /// separated by blank lines, with list values separated by spaces.
+    pub fn to_text(&self) -> String {
+        match *self {
+            Value::Str(ref s) => s.clone(),
+            Value::List(ref l) => {
+                let items: Vec<String> = l.iter().map(|v| v.to_text())
+                                         .collect();
+                items.join("\n\n")
+            },
+            Value::Map(ref m) => {
+                m.iter().map(|(k, v)| {
+                    match *v {
+                        Value::List(ref l) => {
+                            format!("{}: {}", k, l.iter().fold(
+                                String::new(), |acc, x| {
+                                    acc + " " + &x.to_text()
+                                }))
+                        },
+                        _ => format!("{}: {}", k, v.to_text())
+                    }
+                }).fold(String::new(), |acc, x| {
+                    acc + "\n\n" + &x
+                })
+            },
+        }
+    }
+}
+
+// Filter names with the minimum and maximum number of arguments.
+const FILTERS: [(&'static str, usize, usize); 12] = [
+    ("upper", 0, 0), ("lower", 0, 0), ("trim", 0, 0), ("capitalize", 0, 0),
+    ("length", 0, 0), ("first", 0, 0), ("last", 0, 0), ("sort", 0, 0),
+    ("keys", 0, 0), ("values", 0, 0), ("join", 0, 1), ("default", 1, 1),
+];
+
+struct Filter {
+    name: String,
+    args: Vec<String>
+}
+
+struct Expr {
+    negate: bool,
+    path: Vec<String>,
+    filters: Vec<Filter>
+}
+
+enum Node {
+    Text(String),
+    Output(Expr),
+    // Optional key variable, value variable, iterated expression, body.
+    For(Option<String>, String, Expr, Vec<Node>),
+    // Condition, then branch, else branch.
+    If(Expr, Vec<Node>, Vec<Node>),
+}
+
+enum Tag {
+    For(Option<String>, String, Expr),
+    If(Expr),
+    Else,
+    EndFor,
+    EndIf,
+    Raw,
+}
+
+struct Cursor {
+    chars: Vec<char>,
+    pos: usize
+}
+
+impl Cursor {
+    fn new(s: &str) -> Cursor {
+        Cursor { chars: s.chars().collect(), pos: 0 }
+    }
+
+    fn skip_ws(&mut self) {
+        while self.pos < self.chars.len() &&
+              self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn at_end(&mut self) -> bool {
+        self.skip_ws();
+        self.pos == self.chars.len()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_ws();
+        if self.pos < self.chars.len() && self.chars[self.pos] == c {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn ident(&mut self) -> Option<String> {
+        self.skip_ws();
+        let start = self.pos;
+        while self.pos < self.chars.len() &&
+              (self.chars[self.pos].is_digit(36) ||
+               self.chars[self.pos] == '_') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            None
+        } else {
+            Some(self.chars[start..self.pos].iter().cloned().collect())
+        }
+    }
+
+    // Consumes the given keyword, if it is the next identifier.
+    fn keyword(&mut self, kw: &str) -> bool {
+        let saved = self.pos;
+        match self.ident() {
+            Some(ref id) if id == kw => true,
+            _ => {
+                self.pos = saved;
+                false
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.skip_ws();
+        if self.pos == self.chars.len() {
+            return None;
+        }
+        let quote = self.chars[self.pos];
+        if quote != '"' && quote != '\'' {
+            return None;
+        }
+        let mut res = String::new();
+        let mut i = self.pos + 1;
+        while i < self.chars.len() {
+            let c = self.chars[i];
+            if c == quote {
+                self.pos = i + 1;
+                return Some(res);
+            }
+            if c == '\\' && i + 1 < self.chars.len() {
+                i += 1;
+                res.push(match self.chars[i] {
+                    'n' => '\n',
+                    other => other
+                });
+            } else {
+                res.push(c);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    fn expr(&mut self) -> Option<Expr> {
+        let negate = self.keyword("not");
+        let mut path = Vec::new();
+        match self.ident() {
+            Some(id) => path.push(id),
+            None => { return None; }
+        }
+        // No whitespace is allowed around the dots of a path.
+        while self.pos < self.chars.len() && self.chars[self.pos] == '.' {
+            self.pos += 1;
+            if self.pos < self.chars.len() &&
+               self.chars[self.pos].is_whitespace() {
+                return None;
+            }
+            match self.ident() {
+                Some(id) => path.push(id),
+                None => { return None; }
+            }
+        }
+        let mut filters = Vec::new();
+        while self.eat('|') {
+            let name = match self.ident() {
+                Some(x) => x,
+                None => { return None; }
+            };
+            let mut args = Vec::new();
+            if self.eat('(') {
+                if !self.eat(')') {
+                    loop {
+                        match self.string() {
+                            Some(s) => args.push(s),
+                            None => { return None; }
+                        }
+                        if self.eat(')') {
+                            break;
+                        }
+                        if !self.eat(',') {
+                            return None;
+                        }
+                    }
+                }
+            }
+            filters.push(Filter { name: name, args: args });
+        }
+        Some(Expr { negate: negate, path: path, filters: filters })
+    }
+}
+
+fn check_filters(expr: &Expr) -> Result<(), String> {
+    for f in expr.filters.iter() {
+        match FILTERS.iter().find(|&&(name, _, _)| name == f.name) {
+            Some(&(_, min, max)) => {
+                if f.args.len() < min || f.args.len() > max {
+                    return Err(format!(
+                        "Filter '{}' given {} argument(s), expected {}.",
+                        f.name, f.args.len(),
+                        if min == max { min.to_string() }
+                        else { format!("{} to {}", min, max) }));
+                }
+            },
+            None => {
+                return Err("Unknown filter '".to_string() + &f.name + "'.");
+            }
+        }
+    }
+    Ok(())
+}
+
+// Parses an output expression. Anything which does not look like an
+// expression is left alone, like any other text.
+fn parse_output(s: &str) -> Result<Option<Expr>, String> {
+    let mut cur = Cursor::new(s);
+    match cur.expr() {
+        Some(e) => {
+            if !cur.at_end() || e.negate {
+                return Ok(None);
+            }
+            match check_filters(&e) {
+                Ok(_) => Ok(Some(e)),
+                Err(err) => Err(err)
+            }
+        },
+        None => Ok(None)
+    }
+}
+
+// Parses a block tag. Unknown tags are left alone, like any other text.
+fn parse_tag(s: &str) -> Result<Option<Tag>, String> {
+    let mut cur = Cursor::new(s);
+    let tag = if cur.keyword("for") {
+        let first = match cur.ident() {
+            Some(x) => x,
+            None => { return Err("Malformed 'for' tag.".to_string()); }
+        };
+        let second = if cur.eat(',') { cur.ident() } else { None };
+        if !cur.keyword("in") {
+            return Err("Malformed 'for' tag: expected 'in'.".to_string());
+        }
+        let expr = match cur.expr() {
+            Some(e) => e,
+            None => { return Err("Malformed 'for' tag.".to_string()); }
+        };
+        match second {
+            Some(v) => Tag::For(Some(first), v, expr),
+            None => Tag::For(None, first, expr)
+        }
+    } else if cur.keyword("if") {
+        match cur.expr() {
+            Some(e) => Tag::If(e),
+            None => { return Err("Malformed 'if' tag.".to_string()); }
+        }
+    } else if cur.keyword("else") {
+        Tag::Else
+    } else if cur.keyword("endfor") {
+        Tag::EndFor
+    } else if cur.keyword("endif") {
+        Tag::EndIf
+    } else if cur.keyword("raw") {
+        Tag::Raw
+    } else {
+        return Ok(None);
+    };
+    if !cur.at_end() {
+        return Err("Unexpected text at the end of tag '".to_string() +
+                   s.trim() + "'.");
+    }
+    match tag {
+        Tag::For(_, _, ref e) | Tag::If(ref e) => {
+            match check_filters(e) {
+                Ok(_) => (),
+                Err(err) => { return Err(err); }
+            }
+        },
+        _ => ()
+    }
+    Ok(Some(tag))
+}
+
+enum Frame {
+    Root,
+    For(Option<String>, String, Expr),
+    If(Expr, Option<Vec<Node>>),
+}
+
+fn parse(text: &str, skip_code: bool) -> Result<Vec<Node>, String> {
+    let mut stack: Vec<(Frame, Vec<Node>)> = vec![(Frame::Root, Vec::new())];
+    let mut literal = String::new();
+    // Whether only whitespace has been seen since the last line break.
+    let mut at_line_start = true;
+    let code: Vec<(usize, usize)> = if skip_code {
+        markdown::segments(text).into_iter()
+            .filter(|&(kind, _, _)| kind == markdown::SegmentKind::Code)
+            .map(|(_, start, end)| (start, end)).collect()
+    } else {
+        Vec::new()
+    };
+    let re_endraw = regex::Regex::new(r"\{%\s*endraw\s*%\}").unwrap();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (open, close) = match (rest.find("{{"), rest.find("{%")) {
+            (Some(a), Some(b)) if b < a => (b, "%}"),
+            (Some(a), _) => (a, "}}"),
+            (None, Some(b)) => (b, "%}"),
+            (None, None) => {
+                literal.push_str(rest);
+                break;
+            }
+        };
+        // Code is copied through as it is.
+        let offset = text.len() - rest.len();
+        if let Some(&(_, code_end)) = code.iter().find(|&&(start, end)| {
+            start <= offset + open && offset + open < end
+        }) {
+            literal.push_str(&rest[..code_end - offset]);
+            at_line_start = false;
+            rest = &rest[code_end - offset..];
+            continue;
+        }
+        // A backslash before the braces escapes them.
+        if rest[..open].ends_with('\\') {
+            literal.push_str(&rest[..open - 1]);
+            literal.push_str(&rest[open..open + 2]);
+            at_line_start = false;
+            rest = &rest[open + 2..];
+            continue;
+        }
+        let end = match rest[open + 2..].find(close) {
+            Some(e) => open + 2 + e,
+            None => {
+                literal.push_str(rest);
+                break;
+            }
+        };
+        let inner = &rest[open + 2..end];
+        let after = &rest[end + 2..];
+        if close == "}}" {
+            match parse_output(inner) {
+                Ok(Some(e)) => {
+                    literal.push_str(&rest[..open]);
+                    let top = &mut stack.last_mut().unwrap().1;
+                    top.push(Node::Text(literal.clone()));
+                    top.push(Node::Output(e));
+                    literal.clear();
+                },
+                Ok(None) => { literal.push_str(&rest[..end + 2]); },
+                Err(e) => { return Err(e); }
+            }
+            at_line_start = false;
+            rest = after;
+            continue;
+        }
+        let tag = match parse_tag(inner) {
+            Ok(Some(t)) => t,
+            Ok(None) => {
+                literal.push_str(&rest[..end + 2]);
+                at_line_start = false;
+                rest = after;
+                continue;
+            },
+            Err(e) => { return Err(e); }
+        };
+        // A tag alone on its line does not leave an empty line behind.
+        let before = &rest[..open];
+        let line_start = match before.rfind('\n') {
+            Some(i) => i + 1,
+            None => 0
+        };
+        let alone_before = before[line_start..].trim().is_empty() &&
+                           (line_start > 0 || at_line_start);
+        let line_end = match after.find('\n') {
+            Some(i) => i + 1,
+            None => after.len()
+        };
+        if alone_before && after[..line_end].trim().is_empty() {
+            literal.push_str(&before[..line_start]);
+            at_line_start = true;
+            rest = &after[line_end..];
+        } else {
+            literal.push_str(before);
+            at_line_start = false;
+            rest = after;
+        }
+        stack.last_mut().unwrap().1.push(Node::Text(literal.clone()));
+        literal.clear();
+        match tag {
+            Tag::For(k, v, e) => {
+                stack.push((Frame::For(k, v, e), Vec::new()));
+            },
+            Tag::If(e) => {
+                stack.push((Frame::If(e, None), Vec::new()));
+            },
+            Tag::Else => {
+                match stack.pop() {
+                    Some((Frame::If(e, None), nodes)) => {
+                        stack.push((Frame::If(e, Some(nodes)), Vec::new()));
+                    },
+                    _ => {
+                        return Err("Unexpected '{% else %}'.".to_string());
+                    }
+                }
+            },
+            Tag::EndFor => {
+                match stack.pop() {
+                    Some((Frame::For(k, v, e), nodes)) => {
+                        stack.last_mut().unwrap().1.push(
+                            Node::For(k, v, e, nodes));
+                    },
+                    _ => {
+                        return Err("Unexpected '{% endfor %}'.".to_string());
+                    }
+                }
+            },
+            Tag::Raw => {
+                // Everything up to the matching endraw tag is literal text.
+                let (start, end) = match re_endraw.find(rest) {
+                    Some(x) => x,
+                    None => {
+                        return Err("Missing '{% endraw %}'.".to_string());
+                    }
+                };
+                let raw = &rest[..start];
+                let raw_line_start = match raw.rfind('\n') {
+                    Some(i) => i + 1,
+                    None => 0
+                };
+                let after = &rest[end..];
+                let line_end = match after.find('\n') {
+                    Some(i) => i + 1,
+                    None => after.len()
+                };
+                if raw[raw_line_start..].trim().is_empty() &&
+                   (raw_line_start > 0 || at_line_start) &&
+                   after[..line_end].trim().is_empty() {
+                    literal.push_str(&raw[..raw_line_start]);
+                    at_line_start = true;
+                    rest = &after[line_end..];
+                } else {
+                    literal.push_str(raw);
+                    at_line_start = false;
+                    rest = after;
+                }
+            },
+            Tag::EndIf => {
+                match stack.pop() {
+                    Some((Frame::If(e, None), nodes)) => {
+                        stack.last_mut().unwrap().1.push(
+                            Node::If(e, nodes, Vec::new()));
+                    },
+                    Some((Frame::If(e, Some(then_nodes)), nodes)) => {
+                        stack.last_mut().unwrap().1.push(
+                            Node::If(e, then_nodes, nodes));
+                    },
+                    _ => {
+                        return Err("Unexpected '{% endif %}'.".to_string());
+                    }
+                }
+            },
+        }
+    }
+    stack.last_mut().unwrap().1.push(Node::Text(literal));
+    match stack.pop() {
+        Some((Frame::Root, nodes)) => Ok(nodes),
+        Some((Frame::For(..), _)) => {
+            Err("Missing '{% endfor %}'.".to_string())
+        },
+        _ => Err("Missing '{% endif %}'.".to_string())
+    }
+}
+
+fn apply_filter(f: &Filter, value: Option<Value>)
+-> Result<Option<Value>, String> {
+    let value = match (f.name.as_str(), value) {
+        ("default", None) => {
+            return Ok(Some(Value::Str(f.args[0].clone())));
+        },
+        ("default", Some(ref v)) if !v.is_truthy() => {
+            return Ok(Some(Value::Str(f.args[0].clone())));
+        },
+        (_, None) => { return Ok(None); },
+        (_, Some(v)) => v
+    };
+    let res = match (f.name.as_str(), value) {
+        ("default", v) => v,
+        ("upper", Value::Str(s)) => Value::Str(s.to_uppercase()),
+        ("lower", Value::Str(s)) => Value::Str(s.to_lowercase()),
+        ("trim", Value::Str(s)) => Value::Str(s.trim().to_string()),
+        ("capitalize", Value::Str(s)) => {
+            let mut chars = s.chars();
+            Value::Str(match chars.next() {
+                Some(c) => c.to_uppercase().collect::<String>() +
+                           chars.as_str(),
+                None => String::new()
+            })
+        },
+        ("length", Value::Str(s)) => Value::Str(s.chars().count().to_string()),
+        ("length", Value::List(l)) => Value::Str(l.len().to_string()),
+        ("length", Value::Map(m)) => Value::Str(m.len().to_string()),
+        ("first", Value::List(l)) => {
+            match l.into_iter().next() {
+                Some(v) => v,
+                None => { return Ok(None); }
+            }
+        },
+        ("last", Value::List(l)) => {
+            match l.into_iter().last() {
+                Some(v) => v,
+                None => { return Ok(None); }
+            }
+        },
+        ("sort", Value::List(mut l)) => {
+            l.sort_by(|a, b| a.to_text().cmp(&b.to_text()));
+            Value::List(l)
+        },
+        ("keys", Value::Map(m)) => {
+            Value::List(m.keys().map(|k| Value::Str(k.clone())).collect())
+        },
+        ("values", Value::Map(m)) => {
+            Value::List(m.into_iter().map(|(_, v)| v).collect())
+        },
+        ("join", Value::List(l)) => {
+            let sep = f.args.get(0).map_or("", |s| s.as_str());
+            let items: Vec<String> = l.iter().map(|v| v.to_text()).collect();
+            Value::Str(items.join(sep))
+        },
+        ("join", Value::Str(s)) => Value::Str(s),
+        (name, _) => {
+            return Err("Filter '".to_string() + name + "' cannot be " +
+                       "applied to this kind of value.");
+        }
+    };
+    Ok(Some(res))
+}
+
+struct Scope<'a> {
+    globals: &'a BTreeMap<String, Value>,
+    locals: Vec<(String, Value)>
+}
+
+impl<'a> Scope<'a> {
+    fn lookup(&self, path: &Vec<String>) -> Result<Value, String> {
+        let name = &path[0];
+        let mut value = match self.locals.iter().rev()
+                              .find(|&&(ref n, _)| n == name) {
+            Some(&(_, ref v)) => v.clone(),
+            None => {
+                match self.globals.get(name) {
+                    Some(v) => v.clone(),
+                    None => {
+                        return Err("Variable '".to_string() + name +
+                                   "' is not defined at the point of " +
+                                   "variable substitution.");
+                    }
+                }
+            }
+        };
+        for (i, key) in path.iter().enumerate().skip(1) {
+            let shown = path[..i].join(".");
+            value = match value {
+                Value::Map(mut m) => {
+                    match m.remove(key) {
+                        Some(v) => v,
+                        None => {
+                            return Err("Variable '".to_string() + &shown +
+                                       "' does not contain key '" + key +
+                                       "' at the point of variable " +
+                                       "substitution.");
+                        }
+                    }
+                },
+                _ => {
+                    return Err("Variable '".to_string() + &shown +
+                               "' is not a map and cannot be indexed with " +
+                               "key '" + key + "'.");
+                }
+            };
+        }
+        Ok(value)
+    }
+
+    // Lenient evaluation turns undefined variables and missing keys into
+    // `None` instead of an error; so does a `default` filter.
+    fn eval(&self, expr: &Expr, lenient: bool)
+    -> Result<Option<Value>, String> {
+        let has_default = expr.filters.iter().any(|f| f.name == "default");
+        let mut value = match self.lookup(&expr.path) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                if lenient || has_default {
+                    None
+                } else {
+                    return Err(e);
+                }
+            }
+        };
+        for f in expr.filters.iter() {
+            value = match apply_filter(f, value) {
+                Ok(v) => v,
+                Err(e) => { return Err(e); }
+            };
+        }
+        Ok(value)
+    }
+
+    fn render(&mut self, nodes: &Vec<Node>, out: &mut String)
+    -> Result<(), String> {
+        for node in nodes.iter() {
+            match *node {
+                Node::Text(ref t) => out.push_str(t),
+                Node::Output(ref e) => {
+                    match self.eval(e, false) {
+                        Ok(Some(v)) => out.push_str(&v.to_text()),
+                        Ok(None) => (),
+                        Err(err) => { return Err(err); }
+                    }
+                },
+                Node::If(ref e, ref then_nodes, ref else_nodes) => {
+                    let cond = match self.eval(e, true) {
+                        Ok(v) => v.map_or(false, |x| x.is_truthy()),
+                        Err(err) => { return Err(err); }
+                    };
+                    let branch = if cond != e.negate {
+                        then_nodes
+                    } else {
+                        else_nodes
+                    };
+                    match self.render(branch, out) {
+                        Ok(_) => (),
+                        Err(err) => { return Err(err); }
+                    }
+                },
+                Node::For(ref k, ref v, ref e, ref body) => {
+                    let (items, is_map): (Vec<(Value, Value)>, bool) =
+                        match self.eval(e, false) {
+                        Ok(Some(Value::List(l))) => {
+                            (l.into_iter().enumerate().map(|(i, x)| {
+                                (Value::Str((i + 1).to_string()), x)
+                            }).collect(), false)
+                        },
+                        Ok(Some(Value::Map(m))) => {
+                            (m.into_iter().map(|(key, x)| {
+                                (Value::Str(key), x)
+                            }).collect(), true)
+                        },
+                        Ok(_) => {
+                            return Err("Cannot iterate over '".to_string() +
+                                       &e.path.join(".") + "', which is " +
+                                       "not a list or a map.");
+                        },
+                        Err(err) => { return Err(err); }
+                    };
+                    let count = items.len();
+                    for (i, (key, item)) in items.into_iter().enumerate() {
+                        let mut info = BTreeMap::new();
+                        info.insert("index".to_string(),
+                                    Value::Str((i + 1).to_string()));
+                        info.insert("first".to_string(), Value::Str(
+                            if i == 0 { "true" } else { "" }.to_string()));
+                        info.insert("last".to_string(), Value::Str(
+                            if i + 1 == count { "true" } else { "" }
+                            .to_string()));
+                        let saved = self.locals.len();
+                        self.locals.push(("loop".to_string(),
+                                          Value::Map(info)));
+                        match *k {
+                            // With a single loop variable, maps yield keys.
+                            Some(ref kname) => {
+                                self.locals.push((kname.clone(), key));
+                                self.locals.push((v.clone(), item));
+                            },
+                            None => {
+                                self.locals.push((v.clone(),
+                                                  if is_map { key }
+                                                  else { item }));
+                            }
+                        }
+                        let res = self.render(body, out);
+                        self.locals.truncate(saved);
+                        match res {
+                            Ok(_) => (),
+                            Err(err) => { return Err(err); }
+                        }
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renders a template with the given variables. If `skip_code` is set,
+/// template syntax within code blocks and code spans is left as it is.
+pub fn render(text: &str, globals: &BTreeMap<String, Value>, skip_code: bool)
+-> Result<String, String> {
+    let nodes = match parse(text, skip_code) {
+        Ok(n) => n,
+        Err(e) => { return Err(e); }
+    };
+    let mut scope = Scope {
+        globals: globals,
+        locals: Vec::new()
+    };
+    let mut out = String::with_capacity(text.len());
+    match scope.render(&nodes, &mut out) {
+        Ok(_) => Ok(out),
+        Err(e) => Err(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{render, Value};
+
+    fn s(text: &str) -> Value {
+        Value::Str(text.to_string())
+    }
+
+    fn globals() -> BTreeMap<String, Value> {
+        let mut g = BTreeMap::new();
+        g.insert("name".to_string(), s("kosbook"));
+        g.insert("empty".to_string(), s(""));
+        g.insert("authors".to_string(),
+                 Value::List(vec![s("Lamport"), s("Knuth")]));
+        let mut terms = BTreeMap::new();
+        terms.insert("api".to_string(), s("interface"));
+        terms.insert("cli".to_string(), s("command line"));
+        g.insert("terms".to_string(), Value::Map(terms));
+        g
+    }
+
+    fn r(text: &str) -> String {
+        render(text, &globals(), false).unwrap()
+    }
+
+    #[test]
+    fn output_and_filters() {
+        assert_eq!(r("{{ name }} {{name|upper}}"), "kosbook KOSBOOK");
+        assert_eq!(r("{{ name | capitalize }}"), "Kosbook");
+        assert_eq!(r("{{ authors | sort | join(\", \") }}"), "Knuth, Lamport");
+        assert_eq!(r("{{ authors | first }}/{{ authors | last }}"),
+                   "Lamport/Knuth");
+        assert_eq!(r("{{ terms | keys | join }}"), "apicli");
+        assert_eq!(r("{{ authors | length }} {{ name | length }}"), "2 7");
+        assert_eq!(r("{{ missing | default(\"none\") }}"), "none");
+        assert_eq!(r("{{ empty | default(\"none\") }}"), "none");
+        assert_eq!(r("{{ terms.api }}"), "interface");
+        assert_eq!(r("{{ authors }}"), "Lamport\n\nKnuth");
+    }
+
+    #[test]
+    fn errors() {
+        let g = globals();
+        assert!(render("{{ missing }}", &g, false).is_err());
+        assert!(render("{{ name | shout }}", &g, false).is_err());
+        assert!(render("{{ name | first }}", &g, false).is_err());
+        assert!(render("{% if name %}unclosed", &g, false).is_err());
+        assert!(render("{% endfor %}", &g, false).is_err());
+    }
+
+    #[test]
+    fn not_a_reference() {
+        assert_eq!(r("{{#each items}}"), "{{#each items}}");
+    }
+
+    #[test]
+    fn loops() {
+        assert_eq!(r("{% for a in authors %}[{{ a }}]{% endfor %}"),
+                   "[Lamport][Knuth]");
+        assert_eq!(r("{% for i, a in authors %}{{ i }}={{ a }} {% endfor %}"),
+                   "1=Lamport 2=Knuth ");
+        assert_eq!(r("{% for k, v in terms %}{{ k }}: {{ v }};{% endfor %}"),
+                   "api: interface;cli: command line;");
+        assert_eq!(r("{% for k in terms %}{{ k }}{% endfor %}"), "apicli");
+        assert_eq!(r("{% for a in authors %}{{ loop.index }}\
+                      {% if loop.first %}F{% endif %}\
+                      {% if loop.last %}L{% endif %}{% endfor %}"),
+                   "1F2L");
+    }
+
+    #[test]
+    fn conditionals() {
+        assert_eq!(r("{% if name %}yes{% else %}no{% endif %}"), "yes");
+        assert_eq!(r("{% if empty %}yes{% else %}no{% endif %}"), "no");
+        assert_eq!(r("{% if not missing %}none{% endif %}"), "none");
+        // Tags alone on their line are removed with the line.
+        assert_eq!(r("a\n{% if name %}\nb\n{% endif %}\nc\n"), "a\nb\nc\n");
+    }
+
+    #[test]
+    fn literal_braces() {
+        assert_eq!(r("\\{{ name }} \\{% if x %}"), "{{ name }} {% if x %}");
+        assert_eq!(r("{% raw %}{{ name }}{% if x %}{% endraw %}{{ name }}"),
+                   "{{ name }}{% if x %}kosbook");
+    }
+
+    #[test]
+    fn skip_code() {
+        let g = globals();
+        let text = "`{{ name }}` {{ name }}\n\n```\n{{ missing }}\n```\n";
+        assert_eq!(render(text, &g, true).unwrap(),
+                   "`{{ name }}` kosbook\n\n```\n{{ missing }}\n```\n");
+        assert!(render(text, &g, false).is_err());
+    }
+}
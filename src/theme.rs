@@ -0,0 +1,179 @@
+// Copyright 2016 Dario Domizioli
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::fs::File;
+use std::path::Path;
+
+use template;
+
+// The file name of each template, with its default content.
+const TEMPLATES: [(&'static str, &'static str); 7] = [
+    ("page.html", r#"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <meta name="generator" content="kosbook">
+    <title>{{ title }}</title>
+{{ styles }}{{ head }}</head>
+<body>
+
+{{ search }}{{ content }}
+
+{{ scripts }}</body>
+</html>
+"#),
+    ("search.html", concat!(
+        r#"<div class="search">"#, "\n",
+        r#"<input type="search" id="kos_search" placeholder="Search" "#,
+        r#"autocomplete="off">"#, "\n",
+        r#"<ul id="kos_search_results"></ul>"#, "\n",
+        "</div>\n\n")),
+    ("cover.md", concat!(
+        r#"<div class="book_cover"><div class="book_author">{{ author }}"#,
+        r#"</div><div class="book_title"><a id="kos_book_title">{{ title }}"#,
+        r#"</a></div><div class="book_license">(C) {{ author }} - "#,
+        r#"{{ license }}</div></div>"#, "\n\n")),
+    ("toc.md", r#"<div class="toc">
+
+{{ entries }}</div>
+
+"#),
+    ("part_header.md", concat!(
+        r#"<div class="part_{{ part.number }}">"#, "\n\n",
+        r#"<div class="part_title"><a id="{{ part.anchor }}">"#,
+        r#"{{ part.title }}</a></div>"#, "\n\n")),
+    ("part_footer.md", "\n\n</div>\n\n"),
+    ("chapter_header.md",
+     "# <a id=\"{{ chapter.anchor }}\"> {{ chapter.title }}</a>\n\n"),
+];
+
+/// The templates which lay out the book: the HTML page and its search box,
+/// and the CommonMark for the cover, TOC, part headers and footers and
+/// chapter headers.
+pub struct Theme {
+    templates: BTreeMap<&'static str, String>
+}
+
+impl Theme {
+    /// The built-in theme.
+    pub fn default() -> Theme {
+        Theme {
+            templates: TEMPLATES.iter().map(|&(name, text)| {
+                (name, text.to_string())
+            }).collect()
+        }
+    }
+
+    /// Reads a theme directory. Templates which are not in the directory
+    /// are taken from the built-in theme.
+    pub fn load(dir: &str) -> Result<Theme, String> {
+        if !Path::new(dir).is_dir() {
+            return Err("Theme directory '".to_string() + dir +
+                       "' does not exist.");
+        }
+        let mut theme = Theme::default();
+        for &(name, _) in TEMPLATES.iter() {
+            let path = Path::new(dir).join(name);
+            if !path.exists() {
+                continue;
+            }
+            let mut text = String::new();
+            match File::open(&path) {
+                Ok(mut fread) => {
+                    match fread.read_to_string(&mut text) {
+                        Ok(_) => (),
+                        Err(_) => {
+                            return Err("Error reading theme template '"
+                                       .to_string() +
+                                       &path.to_string_lossy() + "'.");
+                        }
+                    }
+                },
+                Err(_) => {
+                    return Err("Error opening theme template '".to_string() +
+                               &path.to_string_lossy() + "'.");
+                }
+            }
+            theme.templates.insert(name, text);
+        }
+        Ok(theme)
+    }
+
+    /// Renders one of the templates, such as "cover.md", with the given
+    /// variables.
+    pub fn render(&self, name: &str,
+                  variables: &BTreeMap<String, template::Value>)
+    -> Result<String, String> {
+        match template::render(&self.templates[name], variables, false) {
+            Ok(x) => Ok(x),
+            Err(e) => Err("In theme template '".to_string() + name + "': " +
+                          &e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+
+    use template::Value;
+
+    use super::Theme;
+
+    fn values(pairs: &[(&str, &str)]) -> BTreeMap<String, Value> {
+        pairs.iter().map(|&(k, v)| {
+            (k.to_string(), Value::Str(v.to_string()))
+        }).collect()
+    }
+
+    #[test]
+    fn default_theme() {
+        let theme = Theme::default();
+        assert_eq!(theme.render("part_footer.md", &BTreeMap::new()),
+                   Ok("\n\n</div>\n\n".to_string()));
+        let mut chapter = BTreeMap::new();
+        chapter.insert("anchor".to_string(), Value::Str("c".to_string()));
+        chapter.insert("title".to_string(), Value::Str("C".to_string()));
+        let mut vars = BTreeMap::new();
+        vars.insert("chapter".to_string(), Value::Map(chapter));
+        assert_eq!(theme.render("chapter_header.md", &vars),
+                   Ok("# <a id=\"c\"> C</a>\n\n".to_string()));
+        let e = theme.render("cover.md", &BTreeMap::new()).unwrap_err();
+        assert!(e.starts_with("In theme template 'cover.md': "), "{}", e);
+    }
+
+    #[test]
+    fn theme_directory() {
+        let dir = env::temp_dir().join("kosbook_theme");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("toc.md"))
+            .and_then(|mut f| f.write_all(b"Contents: {{ entries }}"))
+            .unwrap();
+        let theme = Theme::load(&dir.to_string_lossy()).unwrap();
+        assert_eq!(theme.render("toc.md", &values(&[("entries", "x")])),
+                   Ok("Contents: x".to_string()));
+        // Other templates are the built-in ones.
+        assert_eq!(theme.render("part_footer.md", &BTreeMap::new()),
+                   Theme::default().render("part_footer.md",
+                                           &BTreeMap::new()));
+        assert!(Theme::load(&dir.join("none").to_string_lossy()).is_err());
+    }
+}